@@ -0,0 +1,105 @@
+/// Articulation markers recognized in the labels of notes layer points.
+///
+/// Labels are free text, so only standalone tokens are treated as markers:
+/// `>` (accent), `.` (staccato), `-` (legato) and `(ghost)`. Marker characters
+/// may be combined into a single token, like `>.` for an accented staccato
+/// note. Everything else in the label is ignored.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Articulation {
+    pub accent: bool,
+    pub staccato: bool,
+    pub legato: bool,
+    pub ghost: bool,
+}
+
+impl Articulation {
+    pub fn from_label(label: &str) -> Articulation {
+        let mut articulation = Articulation::default();
+
+        for token in label.split_whitespace() {
+            if token == "(ghost)" {
+                articulation.ghost = true;
+            } else if token.chars().all(|c| matches!(c, '>' | '.' | '-')) {
+                articulation.accent |= token.contains('>');
+                articulation.staccato |= token.contains('.');
+                articulation.legato |= token.contains('-');
+            }
+        }
+
+        articulation
+    }
+
+    pub fn velocity(&self, velocity: u8, accent_boost: u8, ghost_velocity: u8) -> u8 {
+        if self.ghost {
+            ghost_velocity.min(velocity)
+        } else if self.accent {
+            velocity.saturating_add(accent_boost).min(127)
+        } else {
+            velocity
+        }
+    }
+
+    pub fn duration(&self, duration: usize, staccato_factor: f64, legato_factor: f64) -> usize {
+        if self.staccato {
+            (duration as f64 * staccato_factor) as usize
+        } else if self.legato {
+            (duration as f64 * legato_factor) as usize
+        } else {
+            duration
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markers(label: &str) -> (bool, bool, bool, bool) {
+        let articulation = Articulation::from_label(label);
+
+        (
+            articulation.accent,
+            articulation.staccato,
+            articulation.legato,
+            articulation.ghost,
+        )
+    }
+
+    #[test]
+    fn standalone_and_combined_tokens_recognized() {
+        assert_eq!(markers(""), (false, false, false, false));
+        assert_eq!(markers(">"), (true, false, false, false));
+        assert_eq!(markers("snare ."), (false, true, false, false));
+        assert_eq!(markers("- tie"), (false, false, true, false));
+        assert_eq!(markers("(ghost)"), (false, false, false, true));
+        assert_eq!(markers(">."), (true, true, false, false));
+        assert_eq!(markers("  .>  (ghost) "), (true, true, false, true));
+        assert_eq!(markers("> -"), (true, false, true, false));
+    }
+
+    #[test]
+    fn markers_within_words_ignored() {
+        assert_eq!(markers("a>b"), (false, false, false, false));
+        assert_eq!(markers("fig.1"), (false, false, false, false));
+        assert_eq!(markers("half-time"), (false, false, false, false));
+        assert_eq!(markers("(ghost)ly ghost"), (false, false, false, false));
+        assert_eq!(markers("(GHOST)"), (false, false, false, false));
+    }
+
+    #[test]
+    fn articulated_velocity_and_duration() {
+        let accent = Articulation::from_label(">");
+        assert_eq!(accent.velocity(64, 32, 16), 96);
+        assert_eq!(accent.velocity(120, 32, 16), 127);
+
+        let ghost = Articulation::from_label("> (ghost)");
+        assert_eq!(ghost.velocity(64, 32, 16), 16);
+        assert_eq!(ghost.velocity(8, 32, 16), 8);
+
+        assert_eq!(Articulation::from_label(".").duration(1000, 0.5, 1.5), 500);
+        assert_eq!(Articulation::from_label(".").duration(1000, 0.25, 1.5), 250);
+        assert_eq!(Articulation::from_label("-").duration(1000, 0.5, 1.5), 1500);
+        assert_eq!(Articulation::from_label(".-").duration(1000, 0.5, 1.5), 500);
+        assert_eq!(Articulation::default().duration(1000, 0.5, 1.5), 1000);
+    }
+}
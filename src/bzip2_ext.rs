@@ -0,0 +1,166 @@
+use std::io::{self, Read};
+use std::thread;
+
+use bzip2_rs::DecoderReader;
+
+/// Stream header ("BZh" and the block size) followed by the magic of the
+/// first block or the end of stream marker of empty streams. Streams start
+/// byte-aligned, the magic numbers are practically unique in the compressed
+/// data.
+fn is_stream_start(data: &[u8]) -> bool {
+    const BLOCK_MAGIC: [u8; 6] = [0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+    const END_OF_STREAM_MAGIC: [u8; 6] = [0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+
+    data.len() >= 10
+        && data.starts_with(b"BZh")
+        && (b'1'..=b'9').contains(&data[3])
+        && ((data[4..10] == BLOCK_MAGIC) || (data[4..10] == END_OF_STREAM_MAGIC))
+}
+
+fn decompress_streams(streams: &[&[u8]]) -> io::Result<Vec<u8>> {
+    let mut decompressed_data = Vec::new();
+
+    for stream in streams {
+        DecoderReader::new(*stream).read_to_end(&mut decompressed_data)?;
+    }
+
+    Ok(decompressed_data)
+}
+
+/// Decompresses the pieces between the stream starts one after the other. A
+/// piece failing to decompress is merged with the following one, recovering
+/// from stream starts falsely detected in the compressed data.
+fn decompress_streams_merging(data: &[u8], stream_offsets: &[usize]) -> io::Result<Vec<u8>> {
+    let mut decompressed_data = Vec::new();
+    let mut start = 0;
+
+    for &end in stream_offsets.iter().skip(1).chain([&data.len()]) {
+        let mut decompressed_stream = Vec::new();
+
+        match DecoderReader::new(&data[start..end]).read_to_end(&mut decompressed_stream) {
+            Ok(_) => {
+                decompressed_data.extend(decompressed_stream);
+                start = end;
+            }
+            Err(_) if end < data.len() => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(decompressed_data)
+}
+
+fn decompress_in_parallel(streams: &[&[u8]], thread_count: usize) -> io::Result<Vec<u8>> {
+    if (streams.len() == 1) || (thread_count == 1) {
+        return decompress_streams(streams);
+    }
+
+    // Consecutive streams are grouped to keep the output in order
+    let chunk_size = streams.len().div_ceil(thread_count);
+
+    thread::scope(|scope| {
+        let workers = streams
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| decompress_streams(chunk)))
+            .collect::<Vec<_>>();
+
+        let mut decompressed_data = Vec::new();
+
+        for worker in workers {
+            decompressed_data.extend(worker.join().expect("decompression thread panicked")?);
+        }
+
+        Ok(decompressed_data)
+    })
+}
+
+/// Decompresses bzip2 data consisting of one or more concatenated streams.
+/// Files recompressed by parallel compressors (pbzip2, lbzip2) consist of
+/// many streams, these are decompressed on multiple threads. When any of them
+/// fails, the data is decompressed again serially, merging the pieces split
+/// at false stream starts.
+pub fn decompress_bzip2(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream_offsets = (1..data.len())
+        .filter(|&offset| is_stream_start(&data[offset..]))
+        .collect::<Vec<_>>();
+    stream_offsets.insert(0, 0);
+
+    let streams = stream_offsets
+        .iter()
+        .zip(stream_offsets.iter().skip(1).chain([&data.len()]))
+        .map(|(&start, &end)| &data[start..end])
+        .collect::<Vec<_>>();
+
+    let thread_count = thread::available_parallelism().map_or(1, |count| count.get());
+
+    decompress_in_parallel(&streams, thread_count)
+        .or_else(|_| decompress_streams_merging(data, &stream_offsets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // printf 'first stream\n' | bzip2 -9
+    const FIRST_STREAM: [u8; 51] = [
+        0x42, 0x5a, 0x68, 0x39, 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0x2a, 0x04, 0x5d, 0x38, 0x00,
+        0x00, 0x02, 0x51, 0x80, 0x00, 0x10, 0x40, 0x00, 0x23, 0x22, 0x1c, 0x00, 0x20, 0x00, 0x31,
+        0x00, 0x30, 0x20, 0x1a, 0x62, 0x73, 0x0b, 0x49, 0x08, 0x27, 0x1e, 0x2e, 0xe4, 0x8a, 0x70,
+        0xa1, 0x20, 0x54, 0x08, 0xba, 0x70,
+    ];
+
+    // printf 'second stream\n' | bzip2 -9
+    const SECOND_STREAM: [u8; 53] = [
+        0x42, 0x5a, 0x68, 0x39, 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0x84, 0x52, 0x90, 0x94, 0x00,
+        0x00, 0x05, 0xd1, 0x80, 0x00, 0x10, 0x40, 0x00, 0x2e, 0x03, 0x9c, 0x00, 0x20, 0x00, 0x22,
+        0x00, 0xd0, 0xd0, 0x40, 0xd0, 0x34, 0x05, 0x04, 0xbd, 0x38, 0x4f, 0x1e, 0x1e, 0x2e, 0xe4,
+        0x8a, 0x70, 0xa1, 0x21, 0x08, 0xa5, 0x21, 0x28,
+    ];
+
+    const DECOMPRESSED: &[u8] = b"first stream\nsecond stream\n";
+
+    fn multi_stream_data() -> Vec<u8> {
+        [
+            &FIRST_STREAM[..],
+            &SECOND_STREAM,
+            &FIRST_STREAM,
+            &SECOND_STREAM,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn parallel_and_serial_decompression_agree() {
+        let streams = [
+            &FIRST_STREAM[..],
+            &SECOND_STREAM,
+            &FIRST_STREAM,
+            &SECOND_STREAM,
+        ];
+        let serial = decompress_streams(&streams).unwrap();
+
+        assert_eq!(serial, DECOMPRESSED.repeat(2));
+        assert_eq!(decompress_in_parallel(&streams, 2).unwrap(), serial);
+        assert_eq!(decompress_in_parallel(&streams, 8).unwrap(), serial);
+        assert_eq!(decompress_bzip2(&multi_stream_data()).unwrap(), serial);
+    }
+
+    #[test]
+    fn false_stream_starts_merged() {
+        let data = multi_stream_data();
+        let stream_offsets = [0, 20, 51, 104, 130, 155];
+
+        assert_eq!(
+            decompress_streams_merging(&data, &stream_offsets).unwrap(),
+            DECOMPRESSED.repeat(2)
+        );
+    }
+
+    #[test]
+    fn corrupt_data_rejected() {
+        let mut data = multi_stream_data();
+        data[80] ^= 0xff;
+
+        assert!(decompress_bzip2(&data).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use midly::num::{u4, u7};
+
+use crate::error::Sv2MidError;
+use crate::utils::parse_midi_key;
+use crate::MIDI_DRUM_CHANNEL;
+
+/// Layer name to MIDI channel assignments, overriding the assignment by layer
+/// order.
+pub type ChannelMap = HashMap<String, u4>;
+
+/// Notes of a layer routed to two channels around a split key, for split
+/// keyboard setups.
+#[derive(Debug, Clone)]
+pub struct SplitLayer {
+    pub layer_name: String,
+
+    /// Lowest key of the high channel.
+    pub split_key: u7,
+
+    pub low_channel: u4,
+    pub high_channel: u4,
+}
+
+impl SplitLayer {
+    pub fn channel(&self, key: u7) -> u4 {
+        if key >= self.split_key {
+            self.high_channel
+        } else {
+            self.low_channel
+        }
+    }
+}
+
+/// Parses a `LAYER:KEY:LOW:HIGH` split, the layer name may contain `:`
+/// characters. The channels may be prefixed with `ch`.
+pub fn parse_split_layer(input: &str) -> Result<SplitLayer, Box<dyn Error + Send + Sync>> {
+    let mut parts = input.rsplitn(4, ':');
+
+    let (high_channel, low_channel, split_key, layer_name) =
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(high_channel), Some(low_channel), Some(split_key), Some(layer_name)) => {
+                (high_channel, low_channel, split_key, layer_name)
+            }
+            _ => return Err("expected LAYER:KEY:LOW:HIGH, e.g. Keys:60:1:2".into()),
+        };
+
+    let channel = |input: &str| {
+        parse_channel(input.strip_prefix("ch").unwrap_or(input)).map_err(|err| err.to_string())
+    };
+
+    let low_channel = channel(low_channel)?;
+    let high_channel = channel(high_channel)?;
+
+    if low_channel == high_channel {
+        return Err("the low and high channels must differ".into());
+    }
+
+    Ok(SplitLayer {
+        layer_name: layer_name.to_owned(),
+        split_key: parse_midi_key(split_key)?,
+        low_channel,
+        high_channel,
+    })
+}
+
+/// A short note played at the release of every note, for sampler key
+/// switches.
+#[derive(Debug, Copy, Clone)]
+pub struct ReleaseTrigger {
+    pub key: u7,
+    pub channel: u4,
+}
+
+/// Parses a `KEY:CHANNEL` release trigger, the channel may be prefixed with
+/// `ch`.
+pub fn parse_release_trigger(input: &str) -> Result<ReleaseTrigger, Box<dyn Error + Send + Sync>> {
+    let (key, channel) = input
+        .split_once(':')
+        .ok_or("expected KEY:CHANNEL, e.g. 24:3")?;
+
+    Ok(ReleaseTrigger {
+        key: parse_midi_key(key)?,
+        channel: parse_channel(channel.strip_prefix("ch").unwrap_or(channel))
+            .map_err(|err| err.to_string())?,
+    })
+}
+
+/// Parses a `CHANNEL=PROGRAM` program override, the channel may be prefixed
+/// with `ch`. The drum channel is accepted for selecting drum kits.
+pub fn parse_channel_program(input: &str) -> Result<(u4, u7), Box<dyn Error + Send + Sync>> {
+    let (channel, program) = input
+        .split_once('=')
+        .ok_or("expected CHANNEL=PROGRAM, e.g. 1=40")?;
+
+    let channel = channel.trim();
+    let channel = parse_midi_channel(channel.strip_prefix("ch").unwrap_or(channel))
+        .map_err(|err| err.to_string())?;
+
+    match program.trim().parse::<u8>() {
+        Ok(program) if program <= 127 => Ok((channel, u7::from(program))),
+        _ => Err(format!(
+            "invalid MIDI program '{}' (expected 0-127)",
+            program.escape_default()
+        )
+        .into()),
+    }
+}
+
+/// Loads a channel map file. Files with a `.toml` extension hold a
+/// `[channels]` table of `"Name" = channel` assignments, other files one
+/// `Name=channel` assignment per line, where everything after a `#` is a
+/// comment and blank lines are ignored.
+pub fn load_channel_map(path: &Path) -> Result<ChannelMap, Sv2MidError> {
+    let input = fs::read_to_string(path).map_err(|err| Sv2MidError::from(err).in_path(path))?;
+
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        parse_channel_map_toml(path, &input)
+    } else {
+        parse_channel_map_lines(path, &input)
+    }
+}
+
+fn parse_channel_map_lines(path: &Path, input: &str) -> Result<ChannelMap, Sv2MidError> {
+    let mut channel_map = ChannelMap::new();
+
+    for (line_index, line) in input.lines().enumerate() {
+        let line_error =
+            |message: String| format!("{}:{}: {}", path.display(), line_index + 1, message);
+
+        let line = line.split('#').next().unwrap_or_default().trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let (layer_name, channel) = line
+            .rsplit_once('=')
+            .ok_or_else(|| line_error(String::from("expected a 'Name=channel' assignment")))?;
+        let channel = parse_channel(channel.trim()).map_err(|err| line_error(err.to_string()))?;
+
+        assign_channel(&mut channel_map, layer_name.trim().to_owned(), channel)
+            .map_err(line_error)?;
+    }
+
+    Ok(channel_map)
+}
+
+fn parse_channel_map_toml(path: &Path, input: &str) -> Result<ChannelMap, Sv2MidError> {
+    let mut channel_map = ChannelMap::new();
+
+    let document = input
+        .parse::<toml::Value>()
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    for (key, value) in document.as_table().into_iter().flatten() {
+        let channels = match (key.as_str(), value) {
+            ("channels", toml::Value::Table(channels)) => channels,
+            _ => {
+                return Err(format!(
+                    "{}: unsupported key '{}', expected a [channels] table",
+                    path.display(),
+                    key.escape_default()
+                )
+                .into())
+            }
+        };
+
+        for (layer_name, channel) in channels {
+            let entry_error = |message: String| {
+                format!(
+                    "{}: layer '{}': {}",
+                    path.display(),
+                    layer_name.escape_default(),
+                    message
+                )
+            };
+
+            let channel = match channel {
+                toml::Value::Integer(channel) => parse_channel(&channel.to_string())
+                    .map_err(|err| entry_error(err.to_string()))?,
+                _ => return Err(entry_error(String::from("expected an integer channel")).into()),
+            };
+
+            assign_channel(&mut channel_map, layer_name.clone(), channel).map_err(entry_error)?;
+        }
+    }
+
+    Ok(channel_map)
+}
+
+/// Adds an assignment to a channel map, both the layers and the channels may
+/// only be assigned once.
+fn assign_channel(
+    channel_map: &mut ChannelMap,
+    layer_name: String,
+    channel: u4,
+) -> Result<(), String> {
+    if channel_map.contains_key(&layer_name) {
+        return Err(format!(
+            "layer '{}' assigned more than once",
+            layer_name.escape_default()
+        ));
+    }
+
+    if let Some((mapped_layer_name, _)) = channel_map
+        .iter()
+        .find(|&(_, &mapped_channel)| mapped_channel == channel)
+    {
+        return Err(format!(
+            "channel {} already assigned to layer '{}'",
+            channel,
+            mapped_layer_name.escape_default()
+        ));
+    }
+
+    channel_map.insert(layer_name, channel);
+    Ok(())
+}
+
+/// Parses a channel of the notes layers, the drum channel is reserved for the
+/// instants layers.
+fn parse_channel(input: &str) -> Result<u4, Box<dyn Error>> {
+    match parse_midi_channel(input)? {
+        channel if channel.as_int() == MIDI_DRUM_CHANNEL => {
+            Err(format!("channel {} is reserved for drums", channel).into())
+        }
+        channel => Ok(channel),
+    }
+}
+
+fn parse_midi_channel(input: &str) -> Result<u4, Box<dyn Error>> {
+    match input.parse::<u8>() {
+        Ok(channel) if channel <= 15 => Ok(u4::from(channel)),
+        _ => Err(format!(
+            "invalid MIDI channel '{}' (expected 0-15)",
+            input.escape_default()
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_map_accepts_the_drum_channel() {
+        assert_eq!(
+            parse_channel_program("ch9=16").unwrap(),
+            (u4::from(9), u7::from(16))
+        );
+        assert_eq!(
+            parse_channel_program("1 = 40").unwrap(),
+            (u4::from(1), u7::from(40))
+        );
+        assert!(parse_channel_program("16=0").is_err());
+        assert!(parse_channel_program("1=128").is_err());
+
+        assert!(parse_channel("9").is_err());
+        assert!(parse_release_trigger("24:9").is_err());
+    }
+
+    /// Writes a channel map into a temporary file with the given extension,
+    /// and loads it back.
+    fn load(extension: &str, contents: &str) -> Result<ChannelMap, Sv2MidError> {
+        let path = std::env::temp_dir().join(format!(
+            "sv2mid-channel-map-{}-{}.{}",
+            std::process::id(),
+            contents.len(),
+            extension
+        ));
+
+        fs::write(&path, contents).unwrap();
+        let channel_map = load_channel_map(&path);
+        fs::remove_file(&path).unwrap();
+
+        channel_map
+    }
+
+    #[test]
+    fn channel_map_file_rejects_repeated_assignments() {
+        let channel_map = load("txt", "Piano=2\n# Strings\nStrings = 5 # divisi\n").unwrap();
+        assert_eq!(channel_map.len(), 2);
+        assert_eq!(channel_map["Piano"], u4::from(2));
+        assert_eq!(channel_map["Strings"], u4::from(5));
+
+        assert!(load("txt", "Piano=2\nPiano=3\n").is_err());
+        assert!(load("txt", "Piano=2\nStrings=2\n").is_err());
+    }
+
+    #[test]
+    fn toml_channel_map_rejects_repeated_assignments() {
+        let channel_map = load(
+            "toml",
+            "# Layers\n[channels]\nPiano = 2\n\"Solo strings\" = 5 # divisi\n",
+        )
+        .unwrap();
+        assert_eq!(channel_map.len(), 2);
+        assert_eq!(channel_map["Piano"], u4::from(2));
+        assert_eq!(channel_map["Solo strings"], u4::from(5));
+
+        assert!(load("toml", "[channels]\nPiano = 2\nPiano = 3\n").is_err());
+        assert!(load("toml", "[channels]\nPiano = 2\nStrings = 2\n").is_err());
+        assert!(load("toml", "[channels]\nPiano = 9\n").is_err());
+        assert!(load("toml", "[channels]\nPiano = \"2\"\n").is_err());
+        assert!(load("toml", "[programs]\nPiano = 2\n").is_err());
+    }
+}
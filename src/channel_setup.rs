@@ -0,0 +1,148 @@
+use midly::num::{u4, u7};
+use midly::{MetaMessage, MidiMessage, TrackEventKind};
+
+use crate::{
+    MIDI_CONTROLLER_BANK_SELECT, MIDI_CONTROLLER_PAN, MIDI_CONTROLLER_RESET_ALL_CONTROLLERS,
+    MIDI_CONTROLLER_VOLUME,
+};
+
+/// Initial state of the channel of a notes layer.
+pub struct ChannelSetup<'a> {
+    pub channel: u4,
+    pub name: &'a str,
+    pub bank: Option<u7>,
+    pub program: u7,
+    pub volume: u7,
+    pub pan: u7,
+}
+
+impl<'a> ChannelSetup<'a> {
+    /// Setup events at the start of the channel, shared by the single track
+    /// and the track per layer layouts. Tracks of the latter are named after
+    /// the layer, the name comes first then.
+    pub fn events(&self, track_name: bool, reset_controllers: bool) -> Vec<TrackEventKind<'a>> {
+        let mut events = Vec::new();
+
+        if track_name {
+            events.push(TrackEventKind::Meta(MetaMessage::TrackName(
+                self.name.as_bytes(),
+            )));
+        }
+
+        // Clearing leftover pitch bend, sustain, etc. from previously played
+        // material, before the setup below takes effect
+        if reset_controllers {
+            events.push(self.controller(MIDI_CONTROLLER_RESET_ALL_CONTROLLERS, u7::from(0)));
+        }
+
+        events.extend([
+            TrackEventKind::Meta(MetaMessage::MidiChannel(self.channel)),
+            TrackEventKind::Meta(MetaMessage::InstrumentName(self.name.as_bytes())),
+        ]);
+
+        // The bank select takes effect at the next program change
+        if let Some(bank) = self.bank {
+            events.push(self.controller(MIDI_CONTROLLER_BANK_SELECT, bank));
+        }
+
+        events.extend([
+            TrackEventKind::Midi {
+                channel: self.channel,
+                message: MidiMessage::ProgramChange {
+                    program: self.program,
+                },
+            },
+            self.controller(MIDI_CONTROLLER_VOLUME, self.volume),
+            self.controller(MIDI_CONTROLLER_PAN, self.pan),
+        ]);
+
+        events
+    }
+
+    fn controller(&self, controller: u8, value: u7) -> TrackEventKind<'a> {
+        TrackEventKind::Midi {
+            channel: self.channel,
+            message: MidiMessage::Controller {
+                controller: u7::from(controller),
+                value,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL_SETUP: ChannelSetup = ChannelSetup {
+        channel: u4::new(3),
+        name: "Piano",
+        bank: Some(u7::new(1)),
+        program: u7::new(40),
+        volume: u7::new(100),
+        pan: u7::new(64),
+    };
+
+    fn controller(controller: u8, value: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: u4::from(3),
+            message: MidiMessage::Controller {
+                controller: u7::from(controller),
+                value: u7::from(value),
+            },
+        }
+    }
+
+    /// The events following the optional track name and controller reset.
+    fn channel_events() -> Vec<TrackEventKind<'static>> {
+        vec![
+            TrackEventKind::Meta(MetaMessage::MidiChannel(u4::from(3))),
+            TrackEventKind::Meta(MetaMessage::InstrumentName(b"Piano")),
+            controller(MIDI_CONTROLLER_BANK_SELECT, 1),
+            TrackEventKind::Midi {
+                channel: u4::from(3),
+                message: MidiMessage::ProgramChange {
+                    program: u7::from(40),
+                },
+            },
+            controller(MIDI_CONTROLLER_VOLUME, 100),
+            controller(MIDI_CONTROLLER_PAN, 64),
+        ]
+    }
+
+    #[test]
+    fn single_track_events() {
+        assert_eq!(CHANNEL_SETUP.events(false, false), channel_events());
+
+        let mut events = vec![controller(MIDI_CONTROLLER_RESET_ALL_CONTROLLERS, 0)];
+        events.extend(channel_events());
+        assert_eq!(CHANNEL_SETUP.events(false, true), events);
+    }
+
+    #[test]
+    fn track_per_layer_events() {
+        let mut events = vec![TrackEventKind::Meta(MetaMessage::TrackName(b"Piano"))];
+        events.extend(channel_events());
+        assert_eq!(CHANNEL_SETUP.events(true, false), events);
+
+        let mut events = vec![
+            TrackEventKind::Meta(MetaMessage::TrackName(b"Piano")),
+            controller(MIDI_CONTROLLER_RESET_ALL_CONTROLLERS, 0),
+        ];
+        events.extend(channel_events());
+        assert_eq!(CHANNEL_SETUP.events(true, true), events);
+    }
+
+    #[test]
+    fn no_bank_select_without_bank() {
+        let channel_setup = ChannelSetup {
+            bank: None,
+            ..CHANNEL_SETUP
+        };
+
+        assert!(!channel_setup
+            .events(false, false)
+            .contains(&controller(MIDI_CONTROLLER_BANK_SELECT, 1)));
+        assert_eq!(channel_setup.events(false, false).len(), 5);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::error::ConversionError;
+use crate::warning::Warning;
+
+/// Warning reporter shared by every conversion stage of a single project.
+pub struct Diagnostics {
+    strict: bool,
+    quiet: bool,
+    max_warnings: Option<usize>,
+
+    /// Hold the warnings back until `print_deferred_warnings`, instead of
+    /// printing them as they occur.
+    defer_warnings: bool,
+
+    warnings: Vec<Warning>,
+}
+
+impl Diagnostics {
+    pub fn new(
+        strict: bool,
+        quiet: bool,
+        max_warnings: Option<usize>,
+        defer_warnings: bool,
+    ) -> Diagnostics {
+        Diagnostics {
+            strict,
+            quiet,
+            max_warnings,
+            defer_warnings,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn warn(&mut self, warning: Warning) -> Result<(), ConversionError> {
+        if self.strict {
+            return Err(ConversionError::StrictViolation(warning.to_string()));
+        }
+
+        match self.max_warnings {
+            Some(max_warnings) if self.warnings.len() >= max_warnings => {
+                Err(ConversionError::TooManyWarnings(max_warnings))
+            }
+            _ => {
+                if !self.defer_warnings {
+                    eprintln!("warning: {}", warning);
+                }
+
+                self.warnings.push(warning);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reports additional information about a conversion step, unless in quiet mode.
+    pub fn note(&self, message: String) {
+        if !self.quiet {
+            eprintln!("note: {}", message);
+        }
+    }
+
+    /// Prints the warnings held back so far, in their order of occurrence.
+    pub fn print_deferred_warnings(&mut self) {
+        if self.defer_warnings {
+            for warning in self.warnings.iter() {
+                eprintln!("warning: {}", warning);
+            }
+
+            self.defer_warnings = false;
+        }
+    }
+
+    /// Every warning reported so far.
+    pub fn into_warnings(self) -> Vec<Warning> {
+        self.warnings
+    }
+}
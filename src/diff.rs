@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+
+use crate::utils::{midi_note_name, Seconds};
+
+/// A note of a converted project.
+pub struct ExportedNote {
+    pub key: u8,
+    pub start: Seconds,
+    pub end: Seconds,
+}
+
+impl ExportedNote {
+    fn duration(&self) -> f64 {
+        self.end.0 - self.start.0
+    }
+}
+
+/// A text, lyric or marker event of a converted project.
+pub struct ExportedText {
+    pub seconds: Seconds,
+    pub text: String,
+}
+
+/// Notes and text events of a converted project after every transformation,
+/// grouped by the name of the originating layer.
+#[derive(Default)]
+pub struct ExportedEvents {
+    pub notes: BTreeMap<String, Vec<ExportedNote>>,
+    pub texts: BTreeMap<String, Vec<ExportedText>>,
+}
+
+/// Musical differences between two conversions, grouped by layer name and
+/// ordered by time within the layers. Notes are matched by pitch and onset,
+/// text events by content and position, both within `tolerance` seconds.
+pub fn diff_exported_events(
+    old_events: &ExportedEvents,
+    new_events: &ExportedEvents,
+    tolerance: f64,
+    note_octave_offset: i8,
+) -> BTreeMap<String, Vec<(Seconds, String)>> {
+    let mut differences: BTreeMap<String, Vec<(Seconds, String)>> = BTreeMap::new();
+
+    let layer_names = old_events
+        .notes
+        .keys()
+        .chain(new_events.notes.keys())
+        .chain(old_events.texts.keys())
+        .chain(new_events.texts.keys())
+        .collect::<Vec<_>>();
+
+    for layer_name in layer_names {
+        if differences.contains_key(layer_name) {
+            continue;
+        }
+
+        let mut layer_differences = Vec::new();
+
+        let old_notes = old_events
+            .notes
+            .get(layer_name)
+            .map_or(&[][..], Vec::as_slice);
+        let new_notes = new_events
+            .notes
+            .get(layer_name)
+            .map_or(&[][..], Vec::as_slice);
+        diff_notes(
+            old_notes,
+            new_notes,
+            tolerance,
+            note_octave_offset,
+            &mut layer_differences,
+        );
+
+        let old_texts = old_events
+            .texts
+            .get(layer_name)
+            .map_or(&[][..], Vec::as_slice);
+        let new_texts = new_events
+            .texts
+            .get(layer_name)
+            .map_or(&[][..], Vec::as_slice);
+        diff_texts(old_texts, new_texts, tolerance, &mut layer_differences);
+
+        layer_differences.sort_by_key(|&(seconds, _)| seconds);
+        differences.insert(layer_name.clone(), layer_differences);
+    }
+
+    differences.retain(|_, layer_differences| !layer_differences.is_empty());
+    differences
+}
+
+fn diff_notes(
+    old_notes: &[ExportedNote],
+    new_notes: &[ExportedNote],
+    tolerance: f64,
+    note_octave_offset: i8,
+    differences: &mut Vec<(Seconds, String)>,
+) {
+    let note_name = |note: &ExportedNote| midi_note_name(note.key as isize, note_octave_offset);
+
+    let mut old_matched = vec![false; old_notes.len()];
+    let mut new_matched = vec![false; new_notes.len()];
+
+    // Same note, possibly with a different duration
+    for (old_index, new_index) in match_closest(
+        old_notes,
+        new_notes,
+        |old_note, new_note| {
+            (old_note.key == new_note.key)
+                && ((old_note.start.0 - new_note.start.0).abs() <= tolerance)
+        },
+        |old_note, new_note| (old_note.start.0 - new_note.start.0).abs(),
+        &mut old_matched,
+        &mut new_matched,
+    ) {
+        let (old_note, new_note) = (&old_notes[old_index], &new_notes[new_index]);
+
+        if (old_note.duration() - new_note.duration()).abs() > tolerance {
+            differences.push((
+                new_note.start,
+                format!(
+                    "duration of note {} at {} changed from {:.3}s to {:.3}s",
+                    note_name(new_note),
+                    new_note.start,
+                    old_note.duration(),
+                    new_note.duration()
+                ),
+            ));
+        }
+    }
+
+    // Same note at a different position
+    for (old_index, new_index) in match_closest(
+        old_notes,
+        new_notes,
+        |old_note, new_note| {
+            (old_note.key == new_note.key)
+                && ((old_note.duration() - new_note.duration()).abs() <= tolerance)
+        },
+        |old_note, new_note| (old_note.start.0 - new_note.start.0).abs(),
+        &mut old_matched,
+        &mut new_matched,
+    ) {
+        let (old_note, new_note) = (&old_notes[old_index], &new_notes[new_index]);
+
+        differences.push((
+            new_note.start,
+            format!(
+                "note {} moved from {} to {}",
+                note_name(new_note),
+                old_note.start,
+                new_note.start
+            ),
+        ));
+    }
+
+    for (old_note, _) in old_notes
+        .iter()
+        .zip(old_matched)
+        .filter(|&(_, matched)| !matched)
+    {
+        differences.push((
+            old_note.start,
+            format!("note {} removed at {}", note_name(old_note), old_note.start),
+        ));
+    }
+
+    for (new_note, _) in new_notes
+        .iter()
+        .zip(new_matched)
+        .filter(|&(_, matched)| !matched)
+    {
+        differences.push((
+            new_note.start,
+            format!("note {} added at {}", note_name(new_note), new_note.start),
+        ));
+    }
+}
+
+fn diff_texts(
+    old_texts: &[ExportedText],
+    new_texts: &[ExportedText],
+    tolerance: f64,
+    differences: &mut Vec<(Seconds, String)>,
+) {
+    let distance = |old_text: &ExportedText, new_text: &ExportedText| {
+        (old_text.seconds.0 - new_text.seconds.0).abs()
+    };
+
+    let mut old_matched = vec![false; old_texts.len()];
+    let mut new_matched = vec![false; new_texts.len()];
+
+    // Unchanged text events
+    match_closest(
+        old_texts,
+        new_texts,
+        |old_text, new_text| {
+            (old_text.text == new_text.text) && (distance(old_text, new_text) <= tolerance)
+        },
+        distance,
+        &mut old_matched,
+        &mut new_matched,
+    );
+
+    // Rewritten text at the same position
+    for (old_index, new_index) in match_closest(
+        old_texts,
+        new_texts,
+        |old_text, new_text| distance(old_text, new_text) <= tolerance,
+        distance,
+        &mut old_matched,
+        &mut new_matched,
+    ) {
+        let (old_text, new_text) = (&old_texts[old_index], &new_texts[new_index]);
+
+        differences.push((
+            new_text.seconds,
+            format!(
+                "text at {} changed from '{}' to '{}'",
+                new_text.seconds,
+                old_text.text.escape_default(),
+                new_text.text.escape_default()
+            ),
+        ));
+    }
+
+    // Same text at a different position
+    for (old_index, new_index) in match_closest(
+        old_texts,
+        new_texts,
+        |old_text, new_text| old_text.text == new_text.text,
+        distance,
+        &mut old_matched,
+        &mut new_matched,
+    ) {
+        let (old_text, new_text) = (&old_texts[old_index], &new_texts[new_index]);
+
+        differences.push((
+            new_text.seconds,
+            format!(
+                "text '{}' moved from {} to {}",
+                new_text.text.escape_default(),
+                old_text.seconds,
+                new_text.seconds
+            ),
+        ));
+    }
+
+    for (old_text, _) in old_texts
+        .iter()
+        .zip(old_matched)
+        .filter(|&(_, matched)| !matched)
+    {
+        differences.push((
+            old_text.seconds,
+            format!(
+                "text '{}' removed at {}",
+                old_text.text.escape_default(),
+                old_text.seconds
+            ),
+        ));
+    }
+
+    for (new_text, _) in new_texts
+        .iter()
+        .zip(new_matched)
+        .filter(|&(_, matched)| !matched)
+    {
+        differences.push((
+            new_text.seconds,
+            format!(
+                "text '{}' added at {}",
+                new_text.text.escape_default(),
+                new_text.seconds
+            ),
+        ));
+    }
+}
+
+/// Pairs every unmatched old item with the closest matching unmatched new
+/// item, in the order of the old items. The paired items are marked as
+/// matched.
+fn match_closest<T>(
+    old_items: &[T],
+    new_items: &[T],
+    is_match: impl Fn(&T, &T) -> bool,
+    distance: impl Fn(&T, &T) -> f64,
+    old_matched: &mut [bool],
+    new_matched: &mut [bool],
+) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+
+    for (old_index, old_item) in old_items.iter().enumerate() {
+        if old_matched[old_index] {
+            continue;
+        }
+
+        let closest_new_index = new_items
+            .iter()
+            .enumerate()
+            .filter(|&(new_index, new_item)| {
+                !new_matched[new_index] && is_match(old_item, new_item)
+            })
+            .min_by(|&(_, a), &(_, b)| distance(old_item, a).total_cmp(&distance(old_item, b)))
+            .map(|(new_index, _)| new_index);
+
+        if let Some(new_index) = closest_new_index {
+            old_matched[old_index] = true;
+            new_matched[new_index] = true;
+            pairs.push((old_index, new_index));
+        }
+    }
+
+    pairs
+}
@@ -0,0 +1,308 @@
+use std::error::Error;
+use std::path::Path;
+use std::string::FromUtf8Error;
+use std::{fmt, io};
+
+use strong_xml::XmlError;
+
+use crate::sv_model::LayerMismatch;
+
+/// Every error of loading and converting a project.
+#[derive(Debug)]
+pub enum Sv2MidError {
+    /// Reading the project or an auxiliary file, or writing the output failed.
+    Io(io::Error),
+
+    /// The project looks bzip2-compressed but fails to decompress.
+    Decompression(io::Error),
+
+    /// Creating or writing an output file failed.
+    Write(io::Error),
+
+    /// The (decompressed) project is not UTF-8 text.
+    Encoding(FromUtf8Error),
+
+    /// The project is not well-formed XML or doesn't follow the structure of
+    /// Sonic Visualiser projects. The position is the line and column of
+    /// syntax errors.
+    Xml {
+        error: XmlError,
+        position: Option<(u32, u32)>,
+    },
+
+    MissingModel {
+        layer_id: usize,
+        layer_name: String,
+        model_id: usize,
+    },
+
+    MissingDataset {
+        model_id: usize,
+        model_name: String,
+        dataset_id: Option<usize>,
+    },
+
+    MissingPlayParameters {
+        layer_name: String,
+        model_id: usize,
+    },
+
+    /// The model or the dataset of an exported layer doesn't match the
+    /// layer type.
+    LayerMismatch {
+        layer_id: usize,
+        layer_name: String,
+        mismatch: Box<LayerMismatch>,
+    },
+
+    /// Invalid option values and auxiliary input files (channel maps, scales).
+    Options(String),
+
+    Conversion(ConversionError),
+
+    /// Some files of a batch conversion failed, their errors are already reported.
+    BatchFailed {
+        failed_count: usize,
+        file_count: usize,
+    },
+
+    /// An error of the named input, a file path or a label given by the caller.
+    Source {
+        name: String,
+        error: Box<Sv2MidError>,
+    },
+}
+
+impl Sv2MidError {
+    pub fn in_source(self, name: &str) -> Sv2MidError {
+        Sv2MidError::Source {
+            name: name.to_owned(),
+            error: Box::new(self),
+        }
+    }
+
+    pub fn in_path(self, path: &Path) -> Sv2MidError {
+        self.in_source(&path.display().to_string())
+    }
+
+    pub fn in_optional_source(self, name: Option<&str>) -> Sv2MidError {
+        match name {
+            Some(name) => self.in_source(name),
+            None => self,
+        }
+    }
+
+    /// Exit status of the command line tool, following sysexits.h.
+    pub fn exit_code(&self) -> i32 {
+        const EX_USAGE: i32 = 64;
+        const EX_DATAERR: i32 = 65;
+        const EX_NOINPUT: i32 = 66;
+        const EX_SOFTWARE: i32 = 70;
+        const EX_CANTCREAT: i32 = 73;
+        const EX_IOERR: i32 = 74;
+
+        match self {
+            Sv2MidError::Io(err) if err.kind() == io::ErrorKind::NotFound => EX_NOINPUT,
+            Sv2MidError::Io(_) => EX_IOERR,
+            Sv2MidError::Write(_) => EX_CANTCREAT,
+            Sv2MidError::Decompression(_)
+            | Sv2MidError::Encoding(_)
+            | Sv2MidError::Xml { .. }
+            | Sv2MidError::MissingModel { .. }
+            | Sv2MidError::MissingDataset { .. }
+            | Sv2MidError::MissingPlayParameters { .. }
+            | Sv2MidError::LayerMismatch { .. } => EX_DATAERR,
+            Sv2MidError::Options(_) => EX_USAGE,
+            Sv2MidError::Conversion(ConversionError::InvariantViolation(_)) => EX_SOFTWARE,
+            Sv2MidError::Conversion(_) => EX_DATAERR,
+            Sv2MidError::BatchFailed { .. } => 1,
+            Sv2MidError::Source { error, .. } => error.exit_code(),
+        }
+    }
+}
+
+impl fmt::Display for Sv2MidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sv2MidError::Io(err) => write!(f, "{}", err),
+            Sv2MidError::Decompression(err) => {
+                write!(f, "invalid bzip2 data: {}", err)
+            }
+            Sv2MidError::Write(err) => write!(f, "cannot write output: {}", err),
+            Sv2MidError::Encoding(err) => write!(
+                f,
+                "project is not valid UTF-8 text, invalid byte sequence at offset {}",
+                err.utf8_error().valid_up_to()
+            ),
+            // Parser errors already mention the position
+            Sv2MidError::Xml { error, .. } => write!(f, "invalid project: {}", error),
+            Sv2MidError::MissingModel {
+                layer_id,
+                layer_name,
+                model_id,
+            } => write!(
+                f,
+                "layer {} ('{}') references missing model {}",
+                layer_id,
+                layer_name.escape_default(),
+                model_id
+            ),
+            Sv2MidError::MissingDataset {
+                model_id,
+                model_name,
+                dataset_id: Some(dataset_id),
+            } => write!(
+                f,
+                "model {} ('{}') references missing dataset {}",
+                model_id,
+                model_name.escape_default(),
+                dataset_id
+            ),
+            Sv2MidError::MissingDataset {
+                model_id,
+                model_name,
+                dataset_id: None,
+            } => write!(
+                f,
+                "model {} ('{}') has no dataset",
+                model_id,
+                model_name.escape_default()
+            ),
+            Sv2MidError::MissingPlayParameters {
+                layer_name,
+                model_id,
+            } => write!(
+                f,
+                "layer '{}' has no play parameters for model {}",
+                layer_name.escape_default(),
+                model_id
+            ),
+            Sv2MidError::LayerMismatch {
+                layer_id,
+                layer_name,
+                mismatch,
+            } => write!(
+                f,
+                "layer {} ('{}'): {}",
+                layer_id,
+                layer_name.escape_default(),
+                mismatch
+            ),
+            Sv2MidError::Options(message) => write!(f, "{}", message),
+            Sv2MidError::Conversion(err) => write!(f, "{}", err),
+            Sv2MidError::BatchFailed {
+                failed_count,
+                file_count,
+            } => write!(
+                f,
+                "failed to convert {} of {} files",
+                failed_count, file_count
+            ),
+            Sv2MidError::Source { name, error } => write!(f, "{}: {}", name, error),
+        }
+    }
+}
+
+impl Error for Sv2MidError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Sv2MidError::Io(err) | Sv2MidError::Decompression(err) | Sv2MidError::Write(err) => {
+                Some(err)
+            }
+            Sv2MidError::Encoding(err) => Some(err),
+            Sv2MidError::Xml { error, .. } => Some(error),
+            Sv2MidError::Conversion(err) => Some(err),
+            Sv2MidError::Source { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Sv2MidError {
+    fn from(err: io::Error) -> Sv2MidError {
+        Sv2MidError::Io(err)
+    }
+}
+
+impl From<XmlError> for Sv2MidError {
+    fn from(error: XmlError) -> Sv2MidError {
+        let position = match &error {
+            XmlError::Parser(parser_error) => {
+                let position = parser_error.pos();
+                Some((position.row, position.col))
+            }
+            _ => None,
+        };
+
+        Sv2MidError::Xml { error, position }
+    }
+}
+
+impl From<ConversionError> for Sv2MidError {
+    fn from(err: ConversionError) -> Sv2MidError {
+        Sv2MidError::Conversion(err)
+    }
+}
+
+impl From<String> for Sv2MidError {
+    fn from(message: String) -> Sv2MidError {
+        Sv2MidError::Options(message)
+    }
+}
+
+impl From<&str> for Sv2MidError {
+    fn from(message: &str) -> Sv2MidError {
+        Sv2MidError::Options(message.to_owned())
+    }
+}
+
+#[derive(Debug)]
+pub enum ConversionError {
+    StrictViolation(String),
+    NoExportableLayers(Vec<String>),
+    TooManyWarnings(usize),
+    InvalidChannels(Vec<u8>),
+    InvariantViolation(String),
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::StrictViolation(message) => {
+                write!(f, "{} (warnings are errors in strict mode)", message)
+            }
+            ConversionError::NoExportableLayers(ignored_layer_types) => {
+                if ignored_layer_types.is_empty() {
+                    write!(f, "project has no layers to export")
+                } else {
+                    write!(
+                        f,
+                        "project has no exportable layers, ignored layer types: {}",
+                        ignored_layer_types.join(", ")
+                    )
+                }
+            }
+            ConversionError::TooManyWarnings(max_warnings) => write!(
+                f,
+                "aborting: exceeded maximum of {} warnings; fix the SV project and try again",
+                max_warnings
+            ),
+            ConversionError::InvalidChannels(channels) => write!(
+                f,
+                "channels {} requested, a MIDI file has 16 channels (0-15)",
+                channels
+                    .iter()
+                    .map(|channel| channel.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ConversionError::InvariantViolation(message) => write!(
+                f,
+                "internal error: {}; please report this as a bug",
+                message
+            ),
+        }
+    }
+}
+
+impl Error for ConversionError {}
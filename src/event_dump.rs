@@ -0,0 +1,103 @@
+use std::io::{self, Write};
+
+use clap::ArgEnum;
+use midly::num::u7;
+use midly::{MetaMessage, MidiMessage, TrackEventKind};
+
+use crate::midly_ext::TrackEventKindExt;
+use crate::utils::{midi_note_name, Seconds};
+
+#[derive(Debug, Copy, Clone, ArgEnum)]
+pub enum EventDumpFormat {
+    /// Aligned columns for reading
+    Text,
+
+    /// Tab-separated values with a header row
+    Tsv,
+}
+
+/// A generated event as written into the MIDI file.
+pub struct EventDumpLine<'a> {
+    pub track: usize,
+    pub ticks: usize,
+    pub delta: usize,
+    pub seconds: Seconds,
+    pub kind: TrackEventKind<'a>,
+
+    /// Name of the originating layer, `None` for the channel setup and the
+    /// closing events.
+    pub source: Option<&'a str>,
+}
+
+pub fn write_event_dump(
+    writer: &mut dyn Write,
+    lines: &[EventDumpLine],
+    format: EventDumpFormat,
+    note_octave_offset: i8,
+) -> io::Result<()> {
+    if let EventDumpFormat::Tsv = format {
+        writeln!(writer, "track\ttick\tdelta\ttime\tchannel\tevent\tsource")?;
+    }
+
+    for line in lines {
+        let channel = line
+            .kind
+            .channel()
+            .map_or_else(|| String::from("-"), |channel| channel.to_string());
+        let description = describe_event_kind(&line.kind, note_octave_offset);
+        let source = line.source.unwrap_or("-").escape_default();
+
+        match format {
+            EventDumpFormat::Text => writeln!(
+                writer,
+                "{:>3} {:>8} {:>6} {:>12} {:>3} {:<32} {}",
+                line.track, line.ticks, line.delta, line.seconds, channel, description, source
+            )?,
+            EventDumpFormat::Tsv => writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                line.track, line.ticks, line.delta, line.seconds, channel, description, source
+            )?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Short description of an event, e.g. "NoteOn C#4 vel 64" or "CC7=100".
+fn describe_event_kind(kind: &TrackEventKind, note_octave_offset: i8) -> String {
+    let note_name = |key: u7| midi_note_name(key.as_int() as isize, note_octave_offset);
+    let text = |data: &[u8]| String::from_utf8_lossy(data).escape_default().to_string();
+
+    match kind {
+        TrackEventKind::Midi { message, .. } => match message {
+            MidiMessage::NoteOn { key, vel } => format!("NoteOn {} vel {}", note_name(*key), vel),
+            MidiMessage::NoteOff { key, vel } => {
+                format!("NoteOff {} vel {}", note_name(*key), vel)
+            }
+            MidiMessage::Aftertouch { key, vel } => {
+                format!("Aftertouch {} {}", note_name(*key), vel)
+            }
+            MidiMessage::Controller { controller, value } => format!("CC{}={}", controller, value),
+            MidiMessage::ProgramChange { program } => format!("Program {}", program),
+            MidiMessage::ChannelAftertouch { vel } => format!("ChannelAftertouch {}", vel),
+            MidiMessage::PitchBend { bend } => format!("PitchBend {}", bend.as_int()),
+        },
+        TrackEventKind::SysEx(data) => format!("SysEx {} bytes", data.len()),
+        TrackEventKind::Escape(data) => format!("Escape {} bytes", data.len()),
+        TrackEventKind::Meta(message) => match message {
+            MetaMessage::Text(data) => format!("Text '{}'", text(data)),
+            MetaMessage::Lyric(data) => format!("Lyric '{}'", text(data)),
+            MetaMessage::Marker(data) => format!("Marker '{}'", text(data)),
+            MetaMessage::TrackName(data) => format!("TrackName '{}'", text(data)),
+            MetaMessage::InstrumentName(data) => format!("InstrumentName '{}'", text(data)),
+            MetaMessage::Tempo(tempo) => format!("Tempo {}us/beat", tempo),
+            MetaMessage::MidiChannel(channel) => format!("MidiChannel {}", channel),
+            MetaMessage::SequencerSpecific(data) => {
+                format!("SequencerSpecific {} bytes", data.len())
+            }
+            MetaMessage::EndOfTrack => String::from("EndOfTrack"),
+            message => format!("{:?}", message),
+        },
+    }
+}
@@ -0,0 +1,74 @@
+use crate::layer_ids::NON_COMMERCIAL_MANUFACTURER_ID;
+use crate::sv_model::SvLayer;
+
+/// Default colours of Sonic Visualiser, for projects lacking the `colour`
+/// attribute.
+const NAMED_COLOURS: &[(&str, [u8; 3])] = &[
+    ("Black", [0x00, 0x00, 0x00]),
+    ("Red", [0x80, 0x00, 0x00]),
+    ("Blue", [0x00, 0x00, 0x80]),
+    ("Green", [0x00, 0x80, 0x00]),
+    ("Purple", [0xC8, 0x32, 0xFF]),
+    ("Orange", [0xFF, 0x96, 0x32]),
+    ("White", [0xFF, 0xFF, 0xFF]),
+    ("Bright Red", [0xFF, 0x00, 0x00]),
+    ("Bright Blue", [0x1E, 0x96, 0xFF]),
+    ("Bright Green", [0x14, 0xFF, 0x14]),
+    ("Bright Purple", [0xFF, 0x96, 0xC8]),
+    ("Bright Orange", [0xFF, 0xC8, 0x32]),
+];
+
+const LAYER_COLOUR_MAGIC: &[u8; 4] = b"SV2C";
+
+const LAYER_COLOUR_VERSION: u8 = 1;
+
+fn parse_hex_colour(input: &str) -> Option<[u8; 3]> {
+    let hex = input.strip_prefix('#')?;
+
+    if (hex.len() != 6) || !hex.is_ascii() {
+        return None;
+    }
+
+    let component = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).ok();
+    Some([component(0)?, component(2)?, component(4)?])
+}
+
+/// RGB colour of a layer, the explicit value takes precedence over the
+/// colour name. Names are matched case-insensitively and may be given in the
+/// `#RRGGBB` form as well. `None` when the layer has an unknown colour or no
+/// colour at all.
+pub fn layer_colour(layer: &SvLayer) -> Option<[u8; 3]> {
+    layer
+        .colour
+        .as_deref()
+        .and_then(parse_hex_colour)
+        .or_else(|| {
+            let colour_name = layer.colour_name.as_deref()?.trim();
+
+            parse_hex_colour(colour_name).or_else(|| {
+                NAMED_COLOURS
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(colour_name))
+                    .map(|&(_, rgb)| rgb)
+            })
+        })
+}
+
+/// Payload of the sequencer-specific meta event carrying the colour of a
+/// layer, 10 bytes:
+///
+/// | Offset | Size | Content                            |
+/// |--------|------|------------------------------------|
+/// | 0      | 1    | 0x7D (non-commercial ID)           |
+/// | 1      | 4    | "SV2C"                             |
+/// | 5      | 1    | format version, 1                  |
+/// | 6      | 1    | MIDI channel, 0xFF for text layers |
+/// | 7      | 3    | red, green, blue                   |
+pub fn layer_colour_payload(rgb: [u8; 3], channel: Option<u8>) -> Vec<u8> {
+    let mut payload = vec![NON_COMMERCIAL_MANUFACTURER_ID];
+    payload.extend(LAYER_COLOUR_MAGIC);
+    payload.push(LAYER_COLOUR_VERSION);
+    payload.push(channel.unwrap_or(0xFF));
+    payload.extend(rgb);
+    payload
+}
@@ -0,0 +1,44 @@
+use crate::sv_model::{SvLayer, SvModel};
+
+/// Manufacturer ID reserved for non-commercial use, leading the payload as
+/// required for sequencer-specific meta events.
+pub const NON_COMMERCIAL_MANUFACTURER_ID: u8 = 0x7D;
+
+/// Identifies the payload among other non-commercial sequencer-specific events.
+const LAYER_IDS_MAGIC: &[u8; 4] = b"SV2M";
+
+const LAYER_IDS_VERSION: u8 = 1;
+
+/// Absent dataset ids are encoded with all bits set.
+const NO_DATASET_ID: u32 = u32::MAX;
+
+/// Payload of the sequencer-specific meta event identifying the Sonic
+/// Visualiser layer of a track or channel, 22 bytes:
+///
+/// | Offset | Size | Content                          |
+/// |--------|------|----------------------------------|
+/// | 0      | 1    | 0x7D (non-commercial ID)         |
+/// | 1      | 4    | "SV2M"                           |
+/// | 5      | 1    | format version, 1                |
+/// | 6      | 4    | layer id, little-endian u32      |
+/// | 10     | 4    | model id, little-endian u32      |
+/// | 14     | 4    | dataset id, little-endian u32    |
+/// | 18     | 4    | MIDI channel, little-endian u32  |
+///
+/// The channel is 0xFFFFFFFF for layers without a channel of their own (text
+/// layers), just like the dataset id of models without a dataset.
+pub fn layer_ids_payload(layer: &SvLayer, model: &SvModel, channel: Option<u8>) -> Vec<u8> {
+    let mut payload = vec![NON_COMMERCIAL_MANUFACTURER_ID];
+    payload.extend(LAYER_IDS_MAGIC);
+    payload.push(LAYER_IDS_VERSION);
+    payload.extend((layer.id as u32).to_le_bytes());
+    payload.extend((model.id as u32).to_le_bytes());
+    payload.extend(
+        model
+            .dataset
+            .map_or(NO_DATASET_ID, |dataset| dataset as u32)
+            .to_le_bytes(),
+    );
+    payload.extend(channel.map_or(u32::MAX, u32::from).to_le_bytes());
+    payload
+}
@@ -0,0 +1,3597 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::num::{NonZeroU32, NonZeroU8, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::{fs, io, process};
+
+use clap::{ArgEnum, Parser, Subcommand};
+use midly::num::{u15, u28, u4, u7};
+use midly::{
+    Format, Fps, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, Track, TrackEvent,
+    TrackEventKind,
+};
+
+mod bzip2_ext;
+
+mod utils;
+use crate::utils::{
+    midi_note_name, midi_pan, midi_tempo, midi_velocity, parse_layer_value, parse_midi_format,
+    parse_midi_key, parse_nonzero_u32, parse_nonzero_u8, parse_nonzero_usize,
+    parse_positive_literal, parse_smpte_fps, parse_smpte_timecode, NoteRange, Seconds,
+    SmpteTimecode, TickRounding, MIDI_BPM_RANGE,
+};
+
+mod sv_model;
+use crate::sv_model::{
+    FrameUnit, GainCurve, NameSource, SvDocument, SvLayer, SvModel, SvPlayParameters,
+};
+pub use crate::sv_model::{SvDataset, SvDenseRow, SvPoint};
+
+mod midly_ext;
+pub use crate::midly_ext::TrackEventKindExt;
+
+mod articulation;
+use crate::articulation::Articulation;
+
+mod error;
+use crate::error::ConversionError;
+pub use crate::error::Sv2MidError;
+
+mod channel_map;
+use crate::channel_map::{
+    load_channel_map, parse_channel_program, parse_release_trigger, parse_split_layer, ChannelMap,
+    ReleaseTrigger, SplitLayer,
+};
+
+mod scale;
+use crate::scale::load_scale_file;
+
+mod plugin_mappings;
+use crate::plugin_mappings::midi_bank_program;
+
+mod gm_programs;
+use crate::gm_programs::gm_program_name;
+
+mod osc;
+use crate::osc::{osc_message, OutputFormat};
+
+mod layer_colours;
+use crate::layer_colours::{layer_colour, layer_colour_payload};
+
+mod layer_ids;
+use crate::layer_ids::layer_ids_payload;
+
+mod rmid;
+use crate::rmid::{unwrap_rmid, wrap_rmid, Container};
+
+mod channel_setup;
+use crate::channel_setup::ChannelSetup;
+
+mod diagnostics;
+use crate::diagnostics::Diagnostics;
+
+mod warning;
+pub use crate::warning::Warning;
+
+mod event_dump;
+use crate::event_dump::{write_event_dump, EventDumpFormat, EventDumpLine};
+
+mod diff;
+use crate::diff::{diff_exported_events, ExportedEvents, ExportedNote, ExportedText};
+
+mod tempo_map;
+use crate::tempo_map::{parse_tempo_change, validate_tempo_changes, TempoMap};
+
+mod polyphony;
+use crate::polyphony::{limit_polyphony, PolyphonyPriority, Voice};
+
+mod piano_roll;
+use crate::piano_roll::{write_piano_roll, PianoRollNote};
+
+mod track_events;
+use crate::track_events::{
+    check_note_events, dedup_controllers, delta_encode, describe_event, AbsoluteTrackEvent,
+};
+
+const MIDI_DRUM_CHANNEL: u8 = 9;
+const MIDI_NOTES_CHANNELS: [u8; 15] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15];
+const MIDI_PORT_COUNT: u8 = 128;
+
+const MIDI_VELOCITY_DEFAULT: u8 = 64;
+const MIDI_VELOCITY_NONE: u8 = 0;
+
+const MIDI_CONTROLLER_BANK_SELECT: u8 = 0;
+const MIDI_CONTROLLER_VOLUME: u8 = 7;
+const MIDI_CONTROLLER_PAN: u8 = 10;
+const MIDI_CONTROLLER_ALL_SOUND_OFF: u8 = 120;
+const MIDI_CONTROLLER_RESET_ALL_CONTROLLERS: u8 = 121;
+const MIDI_CONTROLLER_ALL_NOTES_OFF: u8 = 123;
+
+const MIDI_PITCH_BEND_RANGE: f64 = 2.0;
+const MIDI_MAX_POLYPHONY: usize = 24;
+const MIDI_FADE_STEPS: usize = 16;
+
+/// A less broken MIDI-exporter for Sonic Visualiser
+#[derive(Debug, Parser)]
+#[clap(author, version, subcommand_negates_reqs = true)]
+struct Args {
+    /// Input project file path, '-' for stdin, or a directory of projects for batch conversion
+    #[clap(required = true)]
+    sv_input_path: Option<PathBuf>,
+
+    /// Converted MIDI file path, or the output directory for batch conversion
+    #[clap(required = true)]
+    midi_output_path: Option<PathBuf>,
+
+    /// Fixed MIDI tempo used for exporting
+    #[clap(short = 't', long, default_value = "120.0", parse(try_from_str = parse_positive_literal))]
+    midi_bpm: f64,
+
+    /// Change the tempo at the given beat, may be repeated in ascending order of beats
+    #[clap(
+        long,
+        value_name = "BEAT:BPM",
+        conflicts_with = "trim-leading-silence",
+        parse(try_from_str = parse_tempo_change)
+    )]
+    tempo_at: Vec<(f64, f64)>,
+
+    /// Number of MIDI ticks per beat
+    #[clap(short = 'x', long, default_value = "1024", parse(try_from_str = parse_nonzero_usize))]
+    midi_ticks_per_beat: NonZeroUsize,
+
+    /// Trim the leading silence before the first note
+    #[clap(short = 's', long)]
+    trim_leading_silence: bool,
+
+    /// Omit the tempo meta event (the tempo is still used for tick conversion)
+    #[clap(long)]
+    no_tempo: bool,
+
+    /// Mute the given layer regardless of its play parameters
+    #[clap(long, value_name = "LAYER")]
+    mute_layer: Vec<String>,
+
+    /// Unmute the given layer regardless of its play parameters
+    #[clap(long, value_name = "LAYER")]
+    unmute_layer: Vec<String>,
+
+    /// Program of the given channels regardless of the instruments of their layers,
+    /// e.g. 1=40,2=73 (comma-separated, without bank selects)
+    #[clap(
+        long,
+        value_name = "CHANNEL=PROGRAM",
+        use_value_delimiter = true,
+        parse(try_from_str = parse_channel_program)
+    )]
+    program_map: Vec<(u4, u7)>,
+
+    /// Mute every layer except the listed ones (comma-separated)
+    #[clap(long, value_name = "LAYERS", use_value_delimiter = true)]
+    solo_layers: Vec<String>,
+
+    /// Apply the articulation markers found in note labels
+    #[clap(long)]
+    label_articulations: bool,
+
+    /// Velocity increase of accented notes
+    #[clap(long, default_value = "32", value_name = "VELOCITY")]
+    accent_velocity_boost: u8,
+
+    /// Velocity of ghost notes
+    #[clap(long, default_value = "16", value_name = "VELOCITY", parse(try_from_str = parse_nonzero_u8))]
+    ghost_velocity: NonZeroU8,
+
+    /// Duration factor of staccato notes
+    #[clap(long, default_value = "0.5", value_name = "FACTOR", parse(try_from_str = parse_positive_literal))]
+    staccato_factor: f64,
+
+    /// Duration factor of legato notes
+    #[clap(long, default_value = "1.5", value_name = "FACTOR", parse(try_from_str = parse_positive_literal))]
+    legato_factor: f64,
+
+    /// Length of the drum notes generated for time instants, in MIDI ticks
+    /// [default: a quarter of a beat]
+    #[clap(long, value_name = "TICKS", parse(try_from_str = parse_nonzero_u32))]
+    drum_note_length: Option<NonZeroU32>,
+
+    /// Play every time instant on this drum note, ignoring the instrument of the layer
+    #[clap(long, value_name = "KEY", parse(try_from_str = parse_midi_key))]
+    flat_drums: Option<u7>,
+
+    /// Keep zero-length notes verbatim, overriding any minimum note length
+    #[clap(long)]
+    keep_zero_length_notes: bool,
+
+    /// SMPTE offset of the track start (hh:mm:ss:ff.ff)
+    #[clap(long, value_name = "TIMECODE", parse(try_from_str = parse_smpte_timecode))]
+    smpte_offset: Option<SmpteTimecode>,
+
+    /// Frame rate of the SMPTE offset (24, 25, 29.97 or 30)
+    #[clap(long, value_name = "FPS", default_value = "30", parse(try_from_str = parse_smpte_fps))]
+    smpte_fps: Fps,
+
+    /// Emit the labels of notes layer points as meta events
+    #[clap(long)]
+    note_labels: bool,
+
+    /// Meta event type used for note labels
+    #[clap(long, arg_enum, value_name = "TYPE", default_value = "text")]
+    note_label_meta: LabelMetaKind,
+
+    /// Transpose every notes layer by the given number of semitones
+    #[clap(
+        long,
+        value_name = "SEMITONES",
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    transpose: i8,
+
+    /// Transpose a single notes layer, on top of the global transposition
+    #[clap(long, value_name = "LAYER=SEMITONES", parse(try_from_str = parse_layer_value))]
+    layer_transpose: Vec<(String, i8)>,
+
+    /// Layer attribute used for naming and matching layers
+    #[clap(long, arg_enum, value_name = "SOURCE", default_value = "auto")]
+    name_source: NameSource,
+
+    /// Unit of the point frames of sparse models
+    #[clap(long, arg_enum, value_name = "UNIT", default_value = "samples")]
+    frame_unit: FrameUnit,
+
+    /// Treat every warning as an error
+    #[clap(long)]
+    strict: bool,
+
+    /// Skip layers whose datasets don't match the layer type instead of failing
+    #[clap(long)]
+    lenient: bool,
+
+    /// Print only warnings and errors
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// Print the MIDI channel and program assigned to each layer
+    #[clap(short = 'V', long, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Name the track after the exporting tempo, for reference
+    #[clap(long)]
+    annotate_tempo_in_name: bool,
+
+    /// Allow exporting projects without any exportable layers
+    #[clap(long)]
+    allow_empty: bool,
+
+    /// Abort the conversion after the given number of warnings
+    #[clap(long, value_name = "COUNT")]
+    max_warnings: Option<usize>,
+
+    /// Print the warnings together at the end of the conversion of each project
+    #[clap(long)]
+    warnings_at_end: bool,
+
+    /// Curve used for mapping the layer gain to MIDI channel volume, saturating at 127 above a gain of 1.27 (linear) or 1.61 (log)
+    #[clap(long, arg_enum, value_name = "CURVE", default_value = "linear")]
+    gain_curve: GainCurve,
+
+    /// Export layers of derived models, like alignment paths
+    #[clap(long)]
+    include_derived: bool,
+
+    /// Export colour 3D plot layers as notes, the bin being the key and the magnitude the velocity
+    #[clap(long)]
+    include_colour3dplot: bool,
+
+    /// Skip layers whose model has the given subtype
+    #[clap(long, value_name = "SUBTYPE")]
+    skip_model_subtype: Vec<String>,
+
+    /// Drop imploded (zero-duration and collapsed) notes from the output
+    #[clap(long)]
+    drop_imploded: bool,
+
+    /// Emit a marker at the start of every exported model
+    #[clap(long)]
+    model_markers: bool,
+
+    /// Minimum note duration in milliseconds
+    #[clap(long, value_name = "MS", parse(try_from_str = parse_positive_literal))]
+    min_note_duration: Option<f64>,
+
+    /// Handling of notes shorter than the minimum note duration
+    #[clap(long, arg_enum, value_name = "ACTION", default_value = "extend")]
+    min_note_action: MinNoteAction,
+
+    /// Play a short KEY note on CHANNEL at the release of every note of the notes layers,
+    /// lasting the minimum note duration or a quarter of a beat
+    #[clap(long, value_name = "KEY:CHANNEL", parse(try_from_str = parse_release_trigger))]
+    release_trigger: Option<ReleaseTrigger>,
+
+    /// Repeat the program change before every note, for devices forgetting it
+    #[clap(long)]
+    program_per_note: bool,
+
+    /// Limit the number of simultaneous notes of a notes layer
+    #[clap(long, value_name = "LAYER=COUNT", parse(try_from_str = parse_layer_value))]
+    max_polyphony: Vec<(String, usize)>,
+
+    /// Notes to keep when limiting the polyphony of a layer
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "PRIORITY",
+        default_value = "keep-loudest"
+    )]
+    polyphony_priority: PolyphonyPriority,
+
+    /// Emit All Notes Off and All Sound Off at the end of the track on every channel carrying notes
+    #[clap(long)]
+    panic_at_end: bool,
+
+    /// Emit Reset All Controllers at the start of the used channels, before the channel setup
+    #[clap(long)]
+    reset_controllers: bool,
+
+    /// Export text layer points having a duration as start/end text event pairs
+    #[clap(long)]
+    text_spans: bool,
+
+    /// Maximum rate of controller, pitch bend and aftertouch events, in Hz
+    #[clap(long, value_name = "HZ", parse(try_from_str = parse_positive_literal))]
+    cc_max_rate: Option<f64>,
+
+    /// Octave shift of the note names in diagnostics, -1 names middle C as C3
+    #[clap(
+        long,
+        value_name = "OCTAVES",
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    note_octave_offset: i8,
+
+    /// Standard MIDI file format: 0 (single track, default) or 1 (track per layer)
+    #[clap(long, value_name = "FORMAT", parse(try_from_str = parse_midi_format))]
+    midi_format: Option<Format>,
+
+    /// Place the events of a text layer on the track of a notes layer in format 1 files
+    /// [default: the notes layer whose name starts with the same word]
+    #[clap(long, value_name = "TEXT_LAYER=NOTES_LAYER", parse(try_from_str = parse_layer_value))]
+    text_track: Vec<(String, String)>,
+
+    /// Assign the notes layers beyond the 15 notes channels to further MIDI ports, implies
+    /// format 1
+    #[clap(long)]
+    multi_port: bool,
+
+    /// Export a text layer as karaoke lyrics (.kar), implies format 1
+    #[clap(long, value_name = "LAYER")]
+    karaoke: Option<String>,
+
+    /// Language of the karaoke lyrics (e.g. ENGL)
+    #[clap(long, value_name = "LANGUAGE", requires = "karaoke")]
+    karaoke_language: Option<String>,
+
+    /// Drop controller events repeating the previous value of the same controller
+    #[clap(long)]
+    dedup_controllers: bool,
+
+    /// Rounding of event positions to MIDI ticks
+    #[clap(long, arg_enum, value_name = "MODE", default_value = "round")]
+    tick_rounding: TickRounding,
+
+    /// Tempo written into the MIDI file, the events are still placed according to --midi-bpm
+    #[clap(
+        long,
+        value_name = "BPM",
+        conflicts_with = "tempo-at",
+        parse(try_from_str = parse_positive_literal)
+    )]
+    display_tempo: Option<f64>,
+
+    /// Verify the ordering and the note pairing of the events, and read the written MIDI file back
+    #[clap(long)]
+    verify: bool,
+
+    /// Spread the notes layers across the stereo field, from 0.0 (center) to 1.0 (hard left to hard right)
+    #[clap(long, value_name = "WIDTH")]
+    stereo_spread: Option<f64>,
+
+    /// Swap left and right of all pan values
+    #[clap(long)]
+    invert_pan: bool,
+
+    /// File assigning MIDI channels to notes layers, one 'Name=channel' per line, or a [channels] table in .toml files
+    #[clap(long, value_name = "FILE", alias = "channel-map-file")]
+    channel_map: Option<PathBuf>,
+
+    /// Route the notes of a layer below the split key to the low channel, the rest to the high channel
+    #[clap(long, value_name = "LAYER:KEY:LOW:HIGH", parse(try_from_str = parse_split_layer))]
+    split_layer: Vec<SplitLayer>,
+
+    /// Perform the whole conversion without writing the MIDI file
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Emit pitch bends for fractional note values, assuming a ±2 semitone bend range
+    #[clap(long)]
+    microtonal: bool,
+
+    /// Print the generated events in their final order to a file, '-' for stdout
+    #[clap(long, value_name = "FILE")]
+    print_events: Option<PathBuf>,
+
+    /// Layout of the event listing
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "FORMAT",
+        default_value = "text",
+        requires = "print-events"
+    )]
+    print_events_format: EventDumpFormat,
+
+    /// Print a piano roll of the converted notes to stderr
+    #[clap(long)]
+    piano_roll: bool,
+
+    /// Lowest MIDI key of the note range, the minimum value of notes layers is mapped onto it
+    #[clap(
+        long,
+        value_name = "KEY",
+        parse(try_from_str = parse_midi_key),
+        requires = "note-range-max"
+    )]
+    note_range_min: Option<u7>,
+
+    /// Highest MIDI key of the note range, the maximum value of notes layers is mapped onto it
+    #[clap(
+        long,
+        value_name = "KEY",
+        parse(try_from_str = parse_midi_key),
+        requires = "note-range-min"
+    )]
+    note_range_max: Option<u7>,
+
+    /// File mapping note values to MIDI keys, one 'value=key' pair per line
+    #[clap(long, value_name = "FILE", conflicts_with = "note-range-min")]
+    scale_file: Option<PathBuf>,
+
+    /// Ramp the volume of the notes channels up from silence at the first note, in seconds
+    #[clap(long, value_name = "SECONDS", parse(try_from_str = parse_positive_literal))]
+    fade_in: Option<f64>,
+
+    /// Ramp the volume of the notes channels down to silence at the last note, in seconds
+    #[clap(long, value_name = "SECONDS", parse(try_from_str = parse_positive_literal))]
+    fade_out: Option<f64>,
+
+    /// File container of the output, RMID is the default for the .rmi extension
+    #[clap(long, arg_enum, value_name = "CONTAINER")]
+    container: Option<Container>,
+
+    /// Format of the converted file
+    #[clap(long, arg_enum, value_name = "FORMAT", default_value = "midi")]
+    output_format: OutputFormat,
+
+    /// Scale the channel volumes so the loudest unmuted notes layer plays at full volume
+    #[clap(long)]
+    normalize_gains: bool,
+
+    /// Identify the source layer, model and dataset of each track in sequencer-specific meta events
+    #[clap(long)]
+    embed_layer_ids: bool,
+
+    /// Carry the colours of the layers in sequencer-specific meta events
+    #[clap(long)]
+    embed_colours: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compare the converted notes and text events of two versions of a project,
+    /// exits with status 1 when they differ
+    Diff {
+        /// Earlier version of the project
+        old_sv_path: PathBuf,
+
+        /// Later version of the project
+        new_sv_path: PathBuf,
+
+        /// Maximum position difference of matching notes and text events, in milliseconds
+        #[clap(
+            long,
+            value_name = "MS",
+            default_value = "10",
+            parse(try_from_str = parse_positive_literal)
+        )]
+        tolerance: f64,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+enum MinNoteAction {
+    Drop,
+    Extend,
+}
+
+#[derive(Debug, Copy, Clone, ArgEnum)]
+enum LabelMetaKind {
+    Text,
+    Marker,
+}
+
+impl LabelMetaKind {
+    fn meta_message<'a>(&self, data: &'a [u8]) -> MetaMessage<'a> {
+        match self {
+            LabelMetaKind::Text => MetaMessage::Text(data),
+            LabelMetaKind::Marker => MetaMessage::Marker(data),
+        }
+    }
+}
+
+impl Args {
+    /// Checks the combinations of options clap can't express.
+    fn validate(&self) -> Result<(), Sv2MidError> {
+        self.validate_mute_overrides()?;
+        self.validate_karaoke()?;
+        self.validate_multi_port()?;
+        self.validate_note_range()?;
+        self.validate_tempo_changes()
+    }
+
+    fn validate_mute_overrides(&self) -> Result<(), Sv2MidError> {
+        let conflicting_layer = self
+            .mute_layer
+            .iter()
+            .find(|&layer_name| {
+                self.unmute_layer.contains(layer_name) || self.solo_layers.contains(layer_name)
+            })
+            .or_else(|| {
+                self.unmute_layer.iter().find(|&layer_name| {
+                    !self.solo_layers.is_empty() && !self.solo_layers.contains(layer_name)
+                })
+            });
+
+        if let Some(layer_name) = conflicting_layer {
+            Err(format!(
+                "conflicting mute instructions for layer '{}'",
+                layer_name.escape_default()
+            )
+            .into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn validate_karaoke(&self) -> Result<(), Sv2MidError> {
+        if self.karaoke.is_some() && (self.midi_format == Some(Format::SingleTrack)) {
+            Err("karaoke files require MIDI file format 1".into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn validate_multi_port(&self) -> Result<(), Sv2MidError> {
+        if self.multi_port && (self.midi_format == Some(Format::SingleTrack)) {
+            Err("multiple ports require MIDI file format 1".into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn validate_note_range(&self) -> Result<(), Sv2MidError> {
+        match (self.note_range_min, self.note_range_max) {
+            (Some(key_min), Some(key_max)) if key_min >= key_max => {
+                Err("the note range minimum must be below its maximum".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_tempo_changes(&self) -> Result<(), Sv2MidError> {
+        Ok(validate_tempo_changes(&self.tempo_at)?)
+    }
+
+    fn diagnostics(&self) -> Diagnostics {
+        Diagnostics::new(
+            self.strict,
+            self.quiet,
+            self.max_warnings,
+            self.warnings_at_end,
+        )
+    }
+
+    fn tempo_map(&self) -> TempoMap {
+        TempoMap::new(self.midi_bpm, &self.tempo_at)
+    }
+
+    fn midi_format(&self) -> Format {
+        match self.midi_format {
+            Some(midi_format) => midi_format,
+            None if self.karaoke.is_some() || self.multi_port => Format::Parallel,
+            None => Format::SingleTrack,
+        }
+    }
+
+    fn midi_extension(&self) -> &'static str {
+        if self.output_format == OutputFormat::Osc {
+            "osc"
+        } else if self.container == Some(Container::Rmid) {
+            "rmi"
+        } else if self.karaoke.is_some() {
+            "kar"
+        } else {
+            "mid"
+        }
+    }
+
+    fn container(&self, midi_output_path: &Path) -> Container {
+        match self.container {
+            Some(container) => container,
+            None if midi_output_path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("rmi")) =>
+            {
+                Container::Rmid
+            }
+            None => Container::Smf,
+        }
+    }
+
+    /// Swaps left and right when requested, for projects mixed on headphones.
+    fn output_pan(&self, pan: f64) -> f64 {
+        if self.invert_pan {
+            -pan
+        } else {
+            pan
+        }
+    }
+
+    fn is_layer_muted(&self, layer_name: &str, project_mute: bool) -> bool {
+        if self.mute_layer.iter().any(|name| name == layer_name) {
+            true
+        } else if self.unmute_layer.iter().any(|name| name == layer_name) {
+            false
+        } else if !self.solo_layers.is_empty() {
+            !self.solo_layers.iter().any(|name| name == layer_name)
+        } else {
+            project_mute
+        }
+    }
+}
+
+/// Entry point of the command line tool, exits the process on errors.
+pub fn run() {
+    let args = Args::parse();
+
+    let result = args.validate().and_then(|()| match &args.command {
+        Some(Command::Diff {
+            old_sv_path,
+            new_sv_path,
+            tolerance,
+        }) => diff_projects(&args, old_sv_path, new_sv_path, tolerance / 1000.0),
+        None => convert(&args).map(|()| false),
+    });
+
+    // Like diff, 1 for differences, errors exit with their sysexits.h status
+    match result {
+        Ok(false) => {}
+        Ok(true) => process::exit(1),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(err.exit_code());
+        }
+    }
+}
+
+fn convert(args: &Args) -> Result<(), Sv2MidError> {
+    let sv_input_path = args.sv_input_path.as_deref().expect("missing input path");
+    let midi_output_path = args
+        .midi_output_path
+        .as_deref()
+        .expect("missing output path");
+
+    if sv_input_path.is_dir() {
+        convert_directory(args, sv_input_path, midi_output_path)
+    } else if midi_output_path.extension().is_none() && (args.midi_extension() != "mid") {
+        // Karaoke, RIFF MIDI and OSC outputs are named after their format,
+        // plain MIDI output paths are kept as given
+        let midi_output_path = midi_output_path.with_extension(args.midi_extension());
+        convert_file(args, sv_input_path, Some(&midi_output_path)).map(drop)
+    } else {
+        convert_file(args, sv_input_path, Some(midi_output_path)).map(drop)
+    }
+}
+
+/// Converts both versions of a project in memory and prints the differences
+/// layer by layer, returns whether there were any.
+fn diff_projects(
+    args: &Args,
+    old_sv_path: &Path,
+    new_sv_path: &Path,
+    tolerance: f64,
+) -> Result<bool, Sv2MidError> {
+    let old_events = convert_file(args, old_sv_path, None)?;
+    let new_events = convert_file(args, new_sv_path, None)?;
+
+    let differences =
+        diff_exported_events(&old_events, &new_events, tolerance, args.note_octave_offset);
+
+    for (layer_name, layer_differences) in differences.iter() {
+        println!("layer '{}':", layer_name.escape_default());
+
+        for (_, difference) in layer_differences.iter() {
+            println!("  {}", difference);
+        }
+    }
+
+    Ok(!differences.is_empty())
+}
+
+fn convert_directory(
+    args: &Args,
+    sv_input_dir: &Path,
+    midi_output_dir: &Path,
+) -> Result<(), Sv2MidError> {
+    let mut sv_input_paths = fs::read_dir(sv_input_dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|err| Sv2MidError::from(err).in_path(sv_input_dir))?;
+
+    sv_input_paths.retain(|path| path.is_file() && path.extension() == Some(OsStr::new("sv")));
+    sv_input_paths.sort();
+
+    if !args.dry_run {
+        fs::create_dir_all(midi_output_dir)
+            .map_err(|err| Sv2MidError::Write(err).in_path(midi_output_dir))?;
+    }
+
+    let mut failed_count = 0;
+    let mut summary = Vec::new();
+
+    for sv_input_path in sv_input_paths.iter() {
+        let midi_output_path = midi_output_dir.join(
+            sv_input_path
+                .with_extension(args.midi_extension())
+                .file_name()
+                .unwrap(),
+        );
+
+        if !args.quiet {
+            eprintln!("converting '{}'", sv_input_path.display());
+        }
+
+        match convert_file(args, sv_input_path, Some(&midi_output_path)) {
+            Ok(_) => summary.push(format!(
+                "converted '{}' -> '{}'",
+                sv_input_path.display(),
+                midi_output_path.display()
+            )),
+            Err(err) => {
+                eprintln!("error: {}", err);
+                failed_count += 1;
+                summary.push(format!("failed '{}': {}", sv_input_path.display(), err));
+            }
+        }
+    }
+
+    if !args.quiet {
+        for line in summary.iter() {
+            eprintln!("{}", line);
+        }
+    }
+
+    if failed_count > 0 {
+        Err(Sv2MidError::BatchFailed {
+            failed_count,
+            file_count: sv_input_paths.len(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Converts a project file, or only returns its exported events without an
+/// output path.
+fn convert_file(
+    args: &Args,
+    sv_input_path: &Path,
+    midi_output_path: Option<&Path>,
+) -> Result<ExportedEvents, Sv2MidError> {
+    let mut sv_document = if sv_input_path == Path::new("-") {
+        SvDocument::from_reader(io::stdin().lock(), Some("<stdin>"))?
+    } else {
+        SvDocument::load(sv_input_path)?
+    };
+    sv_document.apply_frame_unit(args.frame_unit);
+
+    let container = midi_output_path.map_or(Container::Smf, |midi_output_path| {
+        args.container(midi_output_path)
+    });
+    let title = sv_input_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+
+    let mut diagnostics = args.diagnostics();
+    let result = convert_document(args, &sv_document, &title, container, &mut diagnostics);
+    diagnostics.print_deferred_warnings();
+    let (output_data, exported_events) = result?;
+
+    if let Some(midi_output_path) = midi_output_path {
+        if args.dry_run {
+            if !args.quiet {
+                eprintln!(
+                    "note: dry run, '{}' has not been written",
+                    midi_output_path.display()
+                );
+            }
+        } else {
+            fs::write(midi_output_path, &output_data)
+                .map_err(|err| Sv2MidError::Write(err).in_path(midi_output_path))?;
+
+            if args.verify {
+                let written_data = fs::read(midi_output_path)
+                    .map_err(|err| Sv2MidError::from(err).in_path(midi_output_path))?;
+
+                if written_data != output_data {
+                    return Err(ConversionError::InvariantViolation(format!(
+                        "'{}' differs from the converted data when read back",
+                        midi_output_path.display()
+                    ))
+                    .into());
+                }
+            }
+        }
+    }
+
+    Ok(exported_events)
+}
+
+/// Converts a project in memory, `options` being the command line options
+/// without the input and output paths, e.g. `["--midi-format", "1"]`.
+/// Returns the contents of the output file.
+///
+/// This is the entry point for embedding the converter, e.g. into a web page
+/// through WebAssembly. Options reading or writing files (channel maps,
+/// scales, event listings) fail on targets without a filesystem.
+pub fn convert_bytes(input: &[u8], options: &[&str]) -> Result<Vec<u8>, Sv2MidError> {
+    convert_bytes_with_warnings(input, options).map(|(output_data, _)| output_data)
+}
+
+/// Like `convert_bytes`, also returning the warnings of the conversion.
+pub fn convert_bytes_with_warnings(
+    input: &[u8],
+    options: &[&str],
+) -> Result<(Vec<u8>, Vec<Warning>), Sv2MidError> {
+    let args = Args::try_parse_from(
+        ["sv2mid"]
+            .into_iter()
+            .chain(options.iter().copied())
+            .chain(["-", "-"]),
+    )
+    .map_err(|err| Sv2MidError::Options(err.to_string()))?;
+
+    if args.command.is_some() {
+        return Err(Sv2MidError::Options(String::from(
+            "subcommands cannot be used for in-memory conversions",
+        )));
+    }
+
+    args.validate()?;
+
+    let mut sv_document = SvDocument::from_bytes(input, None)?;
+    sv_document.apply_frame_unit(args.frame_unit);
+
+    let mut diagnostics = args.diagnostics();
+    let result = convert_document(
+        &args,
+        &sv_document,
+        "",
+        args.container.unwrap_or(Container::Smf),
+        &mut diagnostics,
+    );
+    diagnostics.print_deferred_warnings();
+    let (output_data, _) = result?;
+
+    Ok((output_data, diagnostics.into_warnings()))
+}
+
+/// Converts a loaded project into the contents of the output file, and the
+/// exported notes and text events for comparisons. The title goes into the
+/// karaoke headers.
+fn convert_document(
+    args: &Args,
+    sv_document: &SvDocument,
+    title: &str,
+    container: Container,
+    diagnostics: &mut Diagnostics,
+) -> Result<(Vec<u8>, ExportedEvents), Sv2MidError> {
+    let smpte_offset = match args.smpte_offset {
+        Some(timecode) => Some(
+            timecode
+                .as_smpte_time(args.smpte_fps)
+                .ok_or("SMPTE offset frame out of range for the frame rate")?,
+        ),
+        None => None,
+    };
+
+    let tempo_map = &args.tempo_map();
+
+    if !args.no_tempo {
+        let (min_bpm, max_bpm) = MIDI_BPM_RANGE;
+
+        let written_bpms = [args.display_tempo.unwrap_or(tempo_map.initial_bpm())]
+            .into_iter()
+            .chain(tempo_map.changes().iter().skip(1).map(|change| change.bpm));
+
+        for bpm in written_bpms {
+            if !(min_bpm..=max_bpm).contains(&bpm) {
+                diagnostics.warn(Warning::TempoOutOfRange {
+                    bpm,
+                    min_bpm,
+                    max_bpm,
+                })?;
+            }
+        }
+    }
+
+    let validation = sv_document.validate();
+
+    for warning in validation.warnings {
+        let is_duplicate_model_ids = matches!(warning, Warning::DuplicateModelIds { .. });
+
+        diagnostics.warn(warning)?;
+        if is_duplicate_model_ids {
+            diagnostics.note("the first model is used for each of these ids".to_owned());
+        }
+    }
+
+    if args.frame_unit == FrameUnit::Samples {
+        for model in sv_document.get_models_with_resolution_frames() {
+            diagnostics.warn(Warning::ResolutionFrames {
+                model_id: model.id,
+                model_name: model.name.clone(),
+                resolution: model.resolution.unwrap_or(1),
+            })?;
+            diagnostics.note("use --frame-unit resolution if the notes are compressed into the start of the project".to_owned());
+        }
+    }
+
+    let is_layer_exported = |layer: &&SvLayer| match sv_document.get_model_by_id(layer.model) {
+        Some(model) if model.is_derived() && !args.include_derived => {
+            diagnostics.note(format!(
+                "skipping layer '{}' of derived model '{}'",
+                layer.midi_name(args.name_source).escape_default(),
+                model.name.escape_default()
+            ));
+            false
+        }
+        Some(SvModel {
+            name,
+            subtype: Some(subtype),
+            ..
+        }) if args.skip_model_subtype.contains(subtype) => {
+            diagnostics.note(format!(
+                "skipping layer '{}' of model '{}' with subtype '{}'",
+                layer.midi_name(args.name_source).escape_default(),
+                name.escape_default(),
+                subtype.escape_default()
+            ));
+            false
+        }
+        _ => true,
+    };
+
+    // Projects without notes layers are fine, they still produce valid MIDI
+    // files with only drum and text events.
+    let mut sv_all_notes_layers = sv_document
+        .get_layers_by_type("notes")
+        .chain(
+            sv_document
+                .get_layers_by_type("colour3dplot")
+                .filter(|_| args.include_colour3dplot),
+        )
+        .filter(is_layer_exported)
+        .collect::<Vec<_>>();
+
+    let mut sv_instants_layers = sv_document
+        .get_layers_by_type("timeinstants")
+        .filter(is_layer_exported)
+        .collect::<Vec<_>>();
+
+    let mut sv_text_layers = sv_document
+        .get_layers_by_type("text")
+        .filter(is_layer_exported)
+        .collect::<Vec<_>>();
+
+    // Mismatching models and datasets usually mean corrupt references of
+    // hand-merged projects
+    for sv_layers in [
+        &mut sv_all_notes_layers,
+        &mut sv_instants_layers,
+        &mut sv_text_layers,
+    ] {
+        let mut exported_layers = Vec::with_capacity(sv_layers.len());
+
+        for layer in sv_layers.drain(..) {
+            match validation.layer_mismatches.get(&layer.id) {
+                Some(mismatch) if args.lenient => {
+                    diagnostics.warn(Warning::LayerSkipped {
+                        layer_name: layer.midi_name(args.name_source).to_owned(),
+                        mismatch: mismatch.clone(),
+                    })?;
+                }
+                Some(mismatch) => {
+                    return Err(Sv2MidError::LayerMismatch {
+                        layer_id: layer.id,
+                        layer_name: layer.name.clone(),
+                        mismatch: Box::new(mismatch.clone()),
+                    });
+                }
+                None => exported_layers.push(layer),
+            }
+        }
+
+        *sv_layers = exported_layers;
+    }
+
+    let scale = match &args.scale_file {
+        Some(scale_path) => Some(load_scale_file(scale_path)?),
+        None => None,
+    };
+
+    let mut channel_map = match &args.channel_map {
+        Some(channel_map_path) => load_channel_map(channel_map_path)?,
+        None => ChannelMap::new(),
+    };
+
+    for layer_name in channel_map.keys() {
+        if !sv_all_notes_layers
+            .iter()
+            .any(|notes_layer| notes_layer.midi_name(args.name_source) == layer_name)
+        {
+            diagnostics.warn(Warning::UnmatchedChannelMapEntry {
+                layer_name: layer_name.clone(),
+            })?;
+        }
+    }
+
+    // Split layers are mapped to their low channel
+    for split_layer in args.split_layer.iter() {
+        if !sv_all_notes_layers
+            .iter()
+            .any(|notes_layer| notes_layer.midi_name(args.name_source) == split_layer.layer_name)
+        {
+            diagnostics.warn(Warning::UnmatchedSplitLayer {
+                layer_name: split_layer.layer_name.clone(),
+            })?;
+        }
+
+        if let Some(mapped_channel) =
+            channel_map.insert(split_layer.layer_name.clone(), split_layer.low_channel)
+        {
+            diagnostics.warn(Warning::ChannelMapEntryOverriddenBySplit {
+                layer_name: split_layer.layer_name.clone(),
+                channel: mapped_channel.as_int(),
+            })?;
+        }
+    }
+
+    // Mapped layers get their channels, the rest is assigned by layer order
+    // from the channels left free by the map, the splits and the release
+    // triggers. Multi-port files continue on the further ports, reusing the
+    // notes channels on each of them.
+    let further_port_count = if args.multi_port {
+        MIDI_PORT_COUNT - 1
+    } else {
+        0
+    };
+
+    let mut free_channels = MIDI_NOTES_CHANNELS
+        .into_iter()
+        .map(u4::from)
+        .filter(|channel| {
+            !channel_map
+                .values()
+                .chain(
+                    args.split_layer
+                        .iter()
+                        .map(|split_layer| &split_layer.high_channel),
+                )
+                .chain(
+                    args.release_trigger
+                        .iter()
+                        .map(|release_trigger| &release_trigger.channel),
+                )
+                .any(|mapped_channel| mapped_channel == channel)
+        })
+        .map(|channel| (0, channel))
+        .chain((1..=further_port_count).flat_map(|port| {
+            MIDI_NOTES_CHANNELS
+                .into_iter()
+                .map(move |channel| (port, u4::from(channel)))
+        }));
+
+    let (notes_layer_ports, sv_notes_layers): (Vec<u8>, Vec<_>) = sv_all_notes_layers
+        .iter()
+        .filter_map(
+            |&notes_layer| match channel_map.get(notes_layer.midi_name(args.name_source)) {
+                Some(&channel) => Some((0, (channel, notes_layer))),
+                None => free_channels
+                    .next()
+                    .map(|(port, channel)| (port, (channel, notes_layer))),
+            },
+        )
+        .unzip();
+
+    if sv_notes_layers.len() < sv_all_notes_layers.len() {
+        diagnostics.warn(Warning::TooManyNotesLayers)?;
+        diagnostics.note("unassignable layers will be dropped".to_owned());
+
+        if !args.multi_port {
+            diagnostics
+                .note("--multi-port addresses further channels in format 1 files".to_owned());
+        }
+    }
+
+    let layer_splits = sv_notes_layers
+        .iter()
+        .map(|&(_, notes_layer)| {
+            args.split_layer.iter().find(|split_layer| {
+                split_layer.layer_name == notes_layer.midi_name(args.name_source)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Every port and channel of the notes layers, including the high channels
+    // of the splits
+    let notes_layer_channels = sv_notes_layers
+        .iter()
+        .zip(notes_layer_ports.iter())
+        .zip(layer_splits.iter())
+        .flat_map(|((&(channel, notes_layer), &port), split_layer)| {
+            [
+                Some((port, channel, notes_layer)),
+                split_layer.map(|split_layer| (port, split_layer.high_channel, notes_layer)),
+            ]
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+
+    for (index, &(port, channel, notes_layer)) in notes_layer_channels.iter().enumerate() {
+        if let Some(&(_, _, other_notes_layer)) = notes_layer_channels[..index]
+            .iter()
+            .find(|&&(other_port, other_channel, _)| (other_port, other_channel) == (port, channel))
+        {
+            diagnostics.warn(Warning::SharedChannel {
+                layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                other_layer_name: other_notes_layer.midi_name(args.name_source).to_owned(),
+                channel: channel.as_int(),
+            })?;
+        }
+    }
+
+    if let Some(release_trigger) = args.release_trigger {
+        if let Some(&(_, _, notes_layer)) = notes_layer_channels
+            .iter()
+            .find(|&&(port, channel, _)| (port, channel) == (0, release_trigger.channel))
+        {
+            diagnostics.warn(Warning::ReleaseTriggerChannelShared {
+                layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                channel: release_trigger.channel.as_int(),
+            })?;
+        }
+    }
+
+    // Safety net across the channel options: shared channels are only warned
+    // about, but the distinct channels of every port together with the
+    // release trigger and the drums have to fit into the MIDI channels
+    let mut port_channels: BTreeMap<u8, BTreeSet<u8>> = BTreeMap::new();
+
+    for &(port, channel, _) in notes_layer_channels.iter() {
+        port_channels
+            .entry(port)
+            .or_default()
+            .insert(channel.as_int());
+    }
+
+    port_channels.entry(0).or_default().extend(
+        args.release_trigger
+            .map(|release_trigger| release_trigger.channel.as_int())
+            .into_iter()
+            .chain((!sv_instants_layers.is_empty()).then_some(MIDI_DRUM_CHANNEL)),
+    );
+
+    for channels in port_channels.values() {
+        check_port_channels(channels)?;
+    }
+
+    // Programs forced onto channels replace the instrument of the layer
+    // together with its bank
+    let program_map = args.program_map.iter().copied().collect::<BTreeMap<_, _>>();
+
+    let channel_bank_program =
+        |channel: u4, bank: Option<u7>, program: u7| match program_map.get(&channel) {
+            Some(&mapped_program) => (None, mapped_program),
+            None => (bank, program),
+        };
+
+    for &channel in program_map.keys() {
+        let drums = (channel.as_int() == MIDI_DRUM_CHANNEL) && !sv_instants_layers.is_empty();
+
+        if !drums
+            && !notes_layer_channels
+                .iter()
+                .any(|&(_, notes_layer_channel, _)| notes_layer_channel == channel)
+        {
+            diagnostics.note(format!(
+                "program map channel {} is not used by any layer",
+                channel
+            ));
+        }
+    }
+
+    // Broken references of hand-edited projects, the conversion stages rely
+    // on every exported layer having a model and a dataset
+    for layer in sv_notes_layers
+        .iter()
+        .map(|&(_, notes_layer)| notes_layer)
+        .chain(sv_instants_layers.iter().copied())
+        .chain(sv_text_layers.iter().copied())
+    {
+        sv_document.get_layer_model_and_dataset(layer)?;
+    }
+
+    for layer in sv_notes_layers
+        .iter()
+        .map(|&(_, notes_layer)| notes_layer)
+        .chain(sv_instants_layers.iter().copied())
+    {
+        if sv_document.get_play_parameters_by_id(layer.model).is_none() {
+            return Err(Sv2MidError::MissingPlayParameters {
+                layer_name: layer.name.clone(),
+                model_id: layer.model,
+            });
+        }
+    }
+
+    // Layers of models with a different sample rate than the main model are
+    // usually derived ones with a resampled rate
+    if let Some(main_model) = sv_document.get_main_model() {
+        let sv_exported_layers = sv_notes_layers
+            .iter()
+            .map(|&(_, notes_layer)| notes_layer)
+            .chain(sv_instants_layers.iter().copied())
+            .chain(sv_text_layers.iter().copied());
+
+        for layer in sv_exported_layers {
+            let model = sv_document
+                .get_model_by_id(layer.model)
+                .expect("layer doesn't have model specified");
+
+            if model.sample_rate != main_model.sample_rate {
+                diagnostics.warn(Warning::SampleRateMismatch {
+                    layer_name: layer.midi_name(args.name_source).to_owned(),
+                    sample_rate: model.sample_rate,
+                    main_sample_rate: main_model.sample_rate,
+                })?;
+            }
+        }
+    }
+
+    // Points of models shared by multiple exported layers are exported once
+    // for each of the layers
+    {
+        let exported_layer_ids = sv_notes_layers
+            .iter()
+            .map(|&(_, notes_layer)| notes_layer)
+            .chain(sv_instants_layers.iter().copied())
+            .chain(sv_text_layers.iter().copied())
+            .map(|layer| layer.id)
+            .collect::<HashSet<_>>();
+
+        let mut exported_model_ids = sv_notes_layers
+            .iter()
+            .map(|&(_, notes_layer)| notes_layer)
+            .chain(sv_instants_layers.iter().copied())
+            .chain(sv_text_layers.iter().copied())
+            .map(|layer| layer.model)
+            .collect::<Vec<_>>();
+
+        exported_model_ids.sort_unstable();
+        exported_model_ids.dedup();
+
+        for model_id in exported_model_ids {
+            let layer_names = sv_document
+                .get_layers_by_model_id(model_id)
+                .filter(|layer| exported_layer_ids.contains(&layer.id))
+                .map(|layer| layer.midi_name(args.name_source).to_owned())
+                .collect::<Vec<_>>();
+
+            if layer_names.len() > 1 {
+                diagnostics.warn(Warning::SharedModel {
+                    model_id,
+                    layer_names,
+                })?;
+            }
+        }
+    }
+
+    if sv_notes_layers.is_empty()
+        && sv_instants_layers.is_empty()
+        && sv_text_layers.is_empty()
+        && !args.allow_empty
+    {
+        let mut ignored_layer_types = sv_document
+            .data
+            .layers
+            .iter()
+            .map(|layer| layer.r#type.clone())
+            .collect::<Vec<_>>();
+
+        ignored_layer_types.sort();
+        ignored_layer_types.dedup();
+
+        return Err(ConversionError::NoExportableLayers(ignored_layer_types).into());
+    }
+
+    let mut midi_document = Smf::new(Header::new(
+        args.midi_format(),
+        Timing::Metrical(u15::from(args.midi_ticks_per_beat.get() as u16)),
+    ));
+
+    let karaoke_layer = match &args.karaoke {
+        Some(layer_name) => Some(
+            sv_text_layers
+                .iter()
+                .copied()
+                .find(|text_layer| text_layer.midi_name(args.name_source) == layer_name)
+                .ok_or_else(|| {
+                    format!(
+                        "karaoke text layer '{}' not found",
+                        layer_name.escape_default()
+                    )
+                })?,
+        ),
+        None => None,
+    };
+
+    let is_karaoke_layer =
+        |layer: &SvLayer| karaoke_layer.is_some_and(|karaoke_layer| karaoke_layer.id == layer.id);
+
+    // Soft Karaoke headers, the file identification goes onto the first track,
+    // the language and the title onto the words track
+    let karaoke_file_headers = ["@KMIDI KARAOKE FILE", "@V0100"];
+    let karaoke_words_headers = args
+        .karaoke_language
+        .iter()
+        .map(|language| format!("@L{}", language))
+        .chain([format!("@T{}", title)])
+        .collect::<Vec<_>>();
+
+    let track_name = format!("sv2mid @ {}bpm", tempo_map.initial_bpm());
+
+    // Start/end labels of the text layer spans, these have to outlive the
+    // track events referencing them
+    let text_span_labels = sv_text_layers
+        .iter()
+        .filter(|&&text_layer| args.text_spans && !is_karaoke_layer(text_layer))
+        .flat_map(|text_layer| {
+            let model = sv_document
+                .get_model_by_id(text_layer.model)
+                .expect("text layer doesn't have model specified");
+
+            let dataset_id = model.dataset.expect("model doesn't have dataset specified");
+            sv_document
+                .get_dataset_by_id(dataset_id)
+                .expect("dataset doesn't exist")
+                .points
+                .iter()
+        })
+        .filter(|point| point.duration.is_some())
+        .map(|point| {
+            (
+                point.label(),
+                (
+                    format!("{} start", point.label()),
+                    format!("{} end", point.label()),
+                ),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    // Parallel (format 1) files get a conductor track for the tempo and the
+    // text events, followed by a track per notes layer and a drum track.
+    // Karaoke files carry the lyrics on a separate words track, right after
+    // the conductor track.
+    // Single track (format 0) files merge everything onto the first track.
+    let parallel = args.midi_format() == Format::Parallel;
+    let words_track_count = usize::from(karaoke_layer.is_some());
+
+    let conductor_track = 0;
+    let words_track = 1;
+    let notes_layer_track = |layer_index: usize| {
+        if parallel {
+            1 + words_track_count + layer_index
+        } else {
+            0
+        }
+    };
+    let drum_track = if parallel {
+        1 + words_track_count + sv_notes_layers.len()
+    } else {
+        0
+    };
+
+    let track_count = if parallel {
+        1 + words_track_count + sv_notes_layers.len() + usize::from(!sv_instants_layers.is_empty())
+    } else {
+        1
+    };
+
+    // The tracks of multi-port files address the port of their notes layer
+    let mut track_ports = vec![0; track_count];
+
+    for (layer_index, &port) in notes_layer_ports.iter().enumerate() {
+        track_ports[notes_layer_track(layer_index)] = port;
+    }
+
+    // Text layers are placed onto the track of the notes layer they are
+    // pinned to, or whose name starts with the same word ("Melody notes" and
+    // "Melody lyrics"), the rest goes onto the conductor track
+    let notes_layer_names = sv_notes_layers
+        .iter()
+        .map(|&(_, notes_layer)| notes_layer.midi_name(args.name_source))
+        .collect::<Vec<_>>();
+
+    for (text_layer_name, notes_layer_name) in args.text_track.iter() {
+        if !notes_layer_names.contains(&notes_layer_name.as_str())
+            || !sv_text_layers
+                .iter()
+                .any(|text_layer| text_layer.midi_name(args.name_source) == text_layer_name)
+        {
+            diagnostics.warn(Warning::UnmatchedTextTrack {
+                text_layer_name: text_layer_name.clone(),
+                notes_layer_name: notes_layer_name.clone(),
+            })?;
+        }
+    }
+
+    let text_layer_track = |text_layer: &SvLayer| {
+        let text_layer_name = text_layer.midi_name(args.name_source);
+
+        let notes_layer_index = match args
+            .text_track
+            .iter()
+            .find(|(pinned_layer_name, _)| pinned_layer_name == text_layer_name)
+        {
+            Some((_, notes_layer_name)) => notes_layer_names
+                .iter()
+                .position(|&layer_name| layer_name == notes_layer_name),
+            None => matching_notes_layer(text_layer_name, &notes_layer_names),
+        };
+
+        if is_karaoke_layer(text_layer) {
+            words_track
+        } else {
+            notes_layer_index.map_or(conductor_track, notes_layer_track)
+        }
+    };
+
+    let layer_ids_payloads = if args.embed_layer_ids {
+        let layer_model = |layer: &SvLayer| {
+            sv_document
+                .get_model_by_id(layer.model)
+                .expect("layer doesn't have model specified")
+        };
+
+        let notes_payloads =
+            sv_notes_layers
+                .iter()
+                .enumerate()
+                .map(|(layer_index, &(channel, notes_layer))| {
+                    (
+                        notes_layer_track(layer_index),
+                        layer_ids_payload(
+                            notes_layer,
+                            layer_model(notes_layer),
+                            Some(channel.as_int()),
+                        ),
+                    )
+                });
+        let instants_payloads = sv_instants_layers.iter().map(|&instants_layer| {
+            (
+                drum_track,
+                layer_ids_payload(
+                    instants_layer,
+                    layer_model(instants_layer),
+                    Some(MIDI_DRUM_CHANNEL),
+                ),
+            )
+        });
+        let text_payloads = sv_text_layers.iter().map(|&text_layer| {
+            (
+                text_layer_track(text_layer),
+                layer_ids_payload(text_layer, layer_model(text_layer), None),
+            )
+        });
+
+        notes_payloads
+            .chain(instants_payloads)
+            .chain(text_payloads)
+            .collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+
+    let mut layer_colour_payloads = Vec::new();
+
+    if args.embed_colours {
+        let exported_layers = sv_notes_layers
+            .iter()
+            .enumerate()
+            .map(|(layer_index, &(channel, notes_layer))| {
+                (
+                    notes_layer,
+                    notes_layer_track(layer_index),
+                    Some(channel.as_int()),
+                )
+            })
+            .chain(
+                sv_instants_layers
+                    .iter()
+                    .map(|&instants_layer| (instants_layer, drum_track, Some(MIDI_DRUM_CHANNEL))),
+            )
+            .chain(
+                sv_text_layers
+                    .iter()
+                    .map(|&text_layer| (text_layer, text_layer_track(text_layer), None)),
+            );
+
+        for (layer, track, channel) in exported_layers {
+            match layer_colour(layer) {
+                Some(rgb) => {
+                    layer_colour_payloads.push((track, layer_colour_payload(rgb, channel)))
+                }
+                None if layer.colour.is_some() || layer.colour_name.is_some() => {
+                    diagnostics.warn(Warning::UnknownColour {
+                        colour: layer
+                            .colour
+                            .as_deref()
+                            .or(layer.colour_name.as_deref())
+                            .unwrap_or_default()
+                            .to_owned(),
+                        layer_name: layer.midi_name(args.name_source).to_owned(),
+                    })?;
+                }
+                None => {}
+            }
+        }
+    }
+
+    let mut midi_tracks = vec![Track::new(); track_count];
+    let mut exported_events = ExportedEvents::default();
+    let mut layer_volumes = Vec::new();
+    let mut osc_messages = Vec::new();
+
+    // The stereo spread distributes the notes layers evenly from left to
+    // right, overriding the pan of the layers
+    let layer_pan =
+        |layer_index: usize, play_parameters: &SvPlayParameters| match args.stereo_spread {
+            Some(width) if sv_notes_layers.len() > 1 => {
+                -width + (layer_index as f64 / (sv_notes_layers.len() - 1) as f64) * 2.0 * width
+            }
+            Some(_) => 0.0,
+            None => play_parameters.pan,
+        };
+
+    // MIDI track initialization
+    // The setup events are written before any of the sorted track events, so
+    // they precede the notes even when those start at the very first tick.
+    {
+        if args.annotate_tempo_in_name {
+            midi_tracks[conductor_track].push(TrackEvent {
+                delta: u28::from(0),
+                kind: TrackEventKind::Meta(MetaMessage::TrackName(track_name.as_bytes())),
+            });
+        }
+
+        if !args.no_tempo {
+            midi_tracks[conductor_track].push(TrackEvent {
+                delta: u28::from(0),
+                kind: TrackEventKind::Meta(MetaMessage::Tempo(midi_tempo(
+                    args.display_tempo.unwrap_or(tempo_map.initial_bpm()),
+                ))),
+            });
+        }
+
+        if let Some(smpte_offset) = smpte_offset {
+            midi_tracks[conductor_track].push(TrackEvent {
+                delta: u28::from(0),
+                kind: TrackEventKind::Meta(MetaMessage::SmpteOffset(smpte_offset)),
+            });
+        }
+
+        if karaoke_layer.is_some() {
+            for header in karaoke_file_headers {
+                midi_tracks[conductor_track].push(TrackEvent {
+                    delta: u28::from(0),
+                    kind: TrackEventKind::Meta(MetaMessage::Text(header.as_bytes())),
+                });
+            }
+
+            midi_tracks[words_track].push(TrackEvent {
+                delta: u28::from(0),
+                kind: TrackEventKind::Meta(MetaMessage::TrackName(b"Words")),
+            });
+
+            for header in karaoke_words_headers.iter() {
+                midi_tracks[words_track].push(TrackEvent {
+                    delta: u28::from(0),
+                    kind: TrackEventKind::Meta(MetaMessage::Text(header.as_bytes())),
+                });
+            }
+        }
+
+        // Loudest volume among the audible notes layers, for normalization
+        let loudest_volume = sv_notes_layers
+            .iter()
+            .filter_map(|&(_, notes_layer)| {
+                let play_parameters = sv_document.get_play_parameters_by_id(notes_layer.model)?;
+
+                (!args.is_layer_muted(
+                    notes_layer.midi_name(args.name_source),
+                    play_parameters.mute,
+                ))
+                .then(|| play_parameters.midi_volume(args.gain_curve).as_int())
+            })
+            .max()
+            .unwrap_or(0);
+
+        for (layer_index, &(channel, notes_layer)) in sv_notes_layers.iter().enumerate() {
+            if !notes_layer.midi_name(args.name_source).is_ascii() {
+                diagnostics.warn(Warning::NonAsciiInstrumentName {
+                    layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                })?;
+                diagnostics.note(
+                    "these instrument names may be mishandled by other music software".to_owned(),
+                );
+            }
+
+            let play_parameters = sv_document
+                .get_play_parameters_by_id(notes_layer.model)
+                .expect("failed to find play parameters");
+
+            if args.stereo_spread.is_some() && (play_parameters.pan != 0.0) {
+                diagnostics.warn(Warning::PanOverriddenBySpread {
+                    layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                })?;
+            }
+
+            let volume = if args.is_layer_muted(
+                notes_layer.midi_name(args.name_source),
+                play_parameters.mute,
+            ) {
+                u7::from(0)
+            } else if args.normalize_gains && (loudest_volume > 0) {
+                let volume = play_parameters.midi_volume(args.gain_curve).as_int() as f64;
+                u7::from((volume * 127.0 / loudest_volume as f64).round() as u8)
+            } else {
+                play_parameters.midi_volume(args.gain_curve)
+            };
+            layer_volumes.push(volume);
+
+            let (bank, program) = midi_bank_program(play_parameters);
+            let (bank, program) = channel_bank_program(channel, bank, program);
+
+            if args.verbose {
+                let (_, dataset) = sv_document.get_layer_model_and_dataset(notes_layer)?;
+
+                eprintln!(
+                    "layer '{}' ({} notes) -> channel {}{}, program {} ({}){}{}{}",
+                    notes_layer.midi_name(args.name_source).escape_default(),
+                    dataset.note_count(),
+                    channel,
+                    port_suffix(args, notes_layer_ports[layer_index]),
+                    program,
+                    gm_program_name(program),
+                    bank.map_or_else(String::new, |bank| format!(", bank {}", bank)),
+                    layer_splits[layer_index].map_or_else(String::new, |split_layer| format!(
+                        ", from {} on channel {}",
+                        midi_note_name(
+                            split_layer.split_key.as_int() as isize,
+                            args.note_octave_offset
+                        ),
+                        split_layer.high_channel
+                    )),
+                    name_fallback_suffix(notes_layer, args.name_source)
+                );
+            }
+
+            // Both channels of split layers are set up alike
+            for channel in [
+                Some(channel),
+                layer_splits[layer_index].map(|split_layer| split_layer.high_channel),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                let (bank, program) = midi_bank_program(play_parameters);
+                let (bank, program) = channel_bank_program(channel, bank, program);
+
+                let channel_setup = ChannelSetup {
+                    channel,
+                    name: notes_layer.midi_name(args.name_source),
+                    bank,
+                    program,
+                    volume,
+                    pan: midi_pan(args.output_pan(layer_pan(layer_index, play_parameters))),
+                };
+
+                for kind in channel_setup.events(parallel, args.reset_controllers) {
+                    midi_tracks[notes_layer_track(layer_index)].push(TrackEvent {
+                        delta: u28::from(0),
+                        kind,
+                    });
+                }
+            }
+        }
+
+        if args.verbose {
+            for &instants_layer in sv_instants_layers.iter() {
+                eprintln!(
+                    "layer '{}' -> channel {} (drums){}",
+                    instants_layer.midi_name(args.name_source).escape_default(),
+                    MIDI_DRUM_CHANNEL,
+                    name_fallback_suffix(instants_layer, args.name_source)
+                );
+            }
+        }
+
+        if args.reset_controllers && !sv_instants_layers.is_empty() {
+            midi_tracks[drum_track].push(TrackEvent {
+                delta: u28::from(0),
+                kind: TrackEventKind::Midi {
+                    channel: u4::from(MIDI_DRUM_CHANNEL),
+                    message: MidiMessage::Controller {
+                        controller: u7::from(MIDI_CONTROLLER_RESET_ALL_CONTROLLERS),
+                        value: u7::from(0),
+                    },
+                },
+            });
+        }
+
+        // A program on the drum channel selects the drum kit (GS, GM2)
+        if let Some(&program) = program_map.get(&u4::from(MIDI_DRUM_CHANNEL)) {
+            if !sv_instants_layers.is_empty() {
+                midi_tracks[drum_track].push(TrackEvent {
+                    delta: u28::from(0),
+                    kind: TrackEventKind::Midi {
+                        channel: u4::from(MIDI_DRUM_CHANNEL),
+                        message: MidiMessage::ProgramChange { program },
+                    },
+                });
+            }
+        }
+
+        // Port and sequencer-specific events head the tracks, following the
+        // track name
+        let head_events = track_ports
+            .iter()
+            .enumerate()
+            .filter(|_| args.multi_port)
+            .map(|(track, &port)| (track, MetaMessage::MidiPort(u7::from(port))))
+            .chain(
+                layer_ids_payloads
+                    .iter()
+                    .chain(layer_colour_payloads.iter())
+                    .map(|(track, payload)| (*track, MetaMessage::SequencerSpecific(payload))),
+            );
+
+        let mut head_event_counts = vec![0; track_count];
+
+        for (track, meta_message) in head_events {
+            let track_name_count = midi_tracks[track]
+                .iter()
+                .take_while(|track_event| {
+                    matches!(
+                        track_event.kind,
+                        TrackEventKind::Meta(MetaMessage::TrackName(..))
+                    )
+                })
+                .count();
+
+            midi_tracks[track].insert(
+                track_name_count + head_event_counts[track],
+                TrackEvent {
+                    delta: u28::from(0),
+                    kind: TrackEventKind::Meta(meta_message),
+                },
+            );
+            head_event_counts[track] += 1;
+        }
+
+        // TODO: Drum channel initialization
+        // The drum channel is constructed by merging multiple time instant
+        // layers. It's not obvious how should channel volume/panning be
+        // initialized. I'm leaving it as default for now.
+    }
+
+    // Emitting MIDI track data
+    {
+        let layer_transposes = args
+            .layer_transpose
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+
+        let max_polyphonies = args
+            .max_polyphony
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+
+        let mut absolute_track_events = Vec::new();
+
+        for (layer_index, &(channel, notes_layer)) in sv_notes_layers.iter().enumerate() {
+            let track = notes_layer_track(layer_index);
+
+            let (model, dataset) = sv_document.get_layer_model_and_dataset(notes_layer)?;
+
+            let transpose = args.transpose as isize
+                + layer_transposes
+                    .get(notes_layer.midi_name(args.name_source))
+                    .copied()
+                    .unwrap_or(0) as isize;
+
+            let play_parameters = sv_document
+                .get_play_parameters_by_id(notes_layer.model)
+                .expect("failed to find play parameters");
+
+            // Remapping abstract pitch values (pitch classes, score positions)
+            // from the value range of the model onto the requested MIDI keys
+            let note_range = match (args.note_range_min, args.note_range_max) {
+                (Some(key_min), Some(key_max)) => match model.value_range() {
+                    Some((value_min, value_max)) if value_min < value_max => Some(NoteRange {
+                        value_min,
+                        value_max,
+                        key_min: key_min.as_int() as f64,
+                        key_max: key_max.as_int() as f64,
+                    }),
+                    _ => {
+                        diagnostics.warn(Warning::MissingValueRange {
+                            layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                        })?;
+                        None
+                    }
+                },
+                _ => None,
+            };
+
+            // Values missing from the scale are converted according to the
+            // units of the model, like without a scale
+            let point_value = |point: &SvPoint| {
+                point.value.map(|value| match (&scale, note_range) {
+                    (Some(scale), _) => scale
+                        .midi_value(value)
+                        .unwrap_or_else(|| model.value_to_midi_key(value)),
+                    (None, Some(note_range)) => note_range.remap(value),
+                    (None, None) => model.value_to_midi_key(value),
+                })
+            };
+
+            let point_key =
+                |point: &SvPoint| point_value(point).map(|value| value.round() as isize);
+
+            // Extended notes must not overlap the next note with the same pitch
+            let mut next_onsets = HashMap::new();
+            {
+                let mut point_indices = (0..dataset.points.len()).collect::<Vec<_>>();
+                point_indices.sort_by_key(|&index| {
+                    (
+                        point_key(&dataset.points[index]),
+                        dataset.points[index].frame,
+                    )
+                });
+
+                for window in point_indices.windows(2) {
+                    let (point, next_point) =
+                        (&dataset.points[window[0]], &dataset.points[window[1]]);
+
+                    if point_key(point) == point_key(next_point) {
+                        next_onsets.insert(window[0], next_point.frame);
+                    }
+                }
+            }
+
+            let point_articulation = |point: &SvPoint| {
+                if args.label_articulations {
+                    Articulation::from_label(point.label())
+                } else {
+                    Articulation::default()
+                }
+            };
+
+            // Four-dimensional datasets carry the level and pan of each note,
+            // dense ones the level
+            let point_velocity = |point: &SvPoint| {
+                let velocity = if (dataset.dimensions == 4) || model.is_dense() {
+                    point.level.map_or(MIDI_VELOCITY_DEFAULT, midi_velocity)
+                } else {
+                    MIDI_VELOCITY_DEFAULT
+                };
+
+                point_articulation(point).velocity(
+                    velocity,
+                    args.accent_velocity_boost,
+                    args.ghost_velocity.get(),
+                )
+            };
+
+            let dropped_voices = match max_polyphonies.get(notes_layer.midi_name(args.name_source))
+            {
+                Some(&max_polyphony) => {
+                    let voices = dataset
+                        .points
+                        .iter()
+                        .map(|point| Voice {
+                            start: point.frame,
+                            end: point.frame + point.duration.unwrap_or_default(),
+                            key: point_key(point).unwrap_or_default(),
+                            velocity: point_velocity(point),
+                        })
+                        .collect::<Vec<_>>();
+
+                    limit_polyphony(&voices, max_polyphony, args.polyphony_priority)
+                }
+                None => HashSet::new(),
+            };
+
+            let mut imploded_note_count = 0;
+            let mut short_note_count = 0;
+
+            for (point_index, point) in dataset.points.iter().enumerate() {
+                if dropped_voices.contains(&point_index) {
+                    continue;
+                }
+
+                let key = point_key(point).expect("notes layer point has no value specified");
+
+                let duration = point
+                    .duration
+                    .expect("notes layer point has no duration specified");
+
+                let note_name = midi_note_name(key + transpose, args.note_octave_offset);
+
+                if let Some(scale) = &scale {
+                    let value = point.value.unwrap_or_default();
+
+                    if scale.midi_value(value).is_none() {
+                        diagnostics.warn(Warning::ValueNotInScale {
+                            value,
+                            layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                            seconds: Seconds::new(point.frame, model.sample_rate),
+                        })?;
+                    }
+                }
+
+                if let Some(note_range) = note_range {
+                    let value = point.value.unwrap_or_default();
+
+                    if !note_range.contains(value) {
+                        diagnostics.warn(Warning::ValueOutOfRange {
+                            value,
+                            layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                            seconds: Seconds::new(point.frame, model.sample_rate),
+                            clamped_note_name: note_name.clone(),
+                        })?;
+                    }
+                }
+
+                let articulation = point_articulation(point);
+                let velocity = point_velocity(point);
+                let note_pan = (dataset.dimensions == 4)
+                    .then(|| point.pan.unwrap_or(layer_pan(layer_index, play_parameters)));
+
+                let mut note_duration =
+                    articulation.duration(duration, args.staccato_factor, args.legato_factor);
+                let mut short_note = false;
+
+                if let Some(min_note_duration) = args.min_note_duration {
+                    let min_duration =
+                        (min_note_duration * model.sample_rate as f64 / 1000.0) as usize;
+
+                    if (note_duration < min_duration)
+                        && !(args.keep_zero_length_notes && (duration == 0))
+                    {
+                        short_note = true;
+
+                        if args.min_note_action == MinNoteAction::Extend {
+                            let max_duration = next_onsets
+                                .get(&point_index)
+                                .map_or(usize::MAX, |&next_onset| next_onset - point.frame);
+
+                            note_duration = note_duration.max(min_duration.min(max_duration));
+                        }
+                    }
+                }
+
+                let seconds_note_on = Seconds::new(point.frame, model.sample_rate);
+                let seconds_note_off = Seconds::new(point.frame + note_duration, model.sample_rate);
+
+                let ticks_note_on = seconds_note_on.as_midi_ticks(
+                    tempo_map,
+                    args.midi_ticks_per_beat,
+                    args.tick_rounding,
+                );
+                let ticks_note_off = seconds_note_off.as_midi_ticks(
+                    tempo_map,
+                    args.midi_ticks_per_beat,
+                    args.tick_rounding,
+                );
+                assert!(ticks_note_on <= ticks_note_off);
+
+                // There's a bug in Sonic Visualiser when accidentally right clicking
+                // while drawing notes it creates an additional collapsed note next to the
+                // drawn note. These collapsed notes fuck up MIDI import in DAWs.
+                // Just warn about these issues, better fix them in the source project
+                // than here.
+                // Zero-length notes are kept silently when explicitly requested,
+                // some users rely on them as triggers.
+                let kept_zero_length_note = args.keep_zero_length_notes && (duration == 0);
+
+                let imploded_note = (duration <= 1) && !kept_zero_length_note;
+
+                if (duration == 0) && !kept_zero_length_note {
+                    // Unlike collapsed notes these are not even audible, the
+                    // NoteOff may get processed before the NoteOn.
+                    diagnostics.warn(Warning::ZeroDurationNote {
+                        point_index,
+                        note_name: note_name.clone(),
+                        frame: point.frame,
+                        layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                        seconds: seconds_note_on,
+                    })?;
+                    diagnostics.note(
+                        "zero-duration notes may cause stuck notes on some synthesizers".to_owned(),
+                    );
+                } else if (duration == 1) && !kept_zero_length_note {
+                    diagnostics.warn(Warning::CollapsedNote {
+                        point_index,
+                        note_name: note_name.clone(),
+                        frame: point.frame,
+                        layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                        seconds: seconds_note_on,
+                    })?;
+                }
+
+                if imploded_note {
+                    imploded_note_count += 1;
+
+                    if args.drop_imploded {
+                        continue;
+                    }
+                }
+
+                if short_note {
+                    short_note_count += 1;
+
+                    if args.min_note_action == MinNoteAction::Drop {
+                        continue;
+                    }
+                }
+
+                if (ticks_note_on == ticks_note_off) && (duration > 0) {
+                    diagnostics.warn(Warning::InsufficientResolution {
+                        note_name: Some(note_name.clone()),
+                        layer_type: String::from("notes"),
+                        layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                        seconds: seconds_note_on,
+                    })?;
+                }
+
+                let transposed_key = key + transpose;
+                let key = transposed_key.clamp(0, 127) as u8;
+
+                let channel = layer_splits[layer_index]
+                    .map_or(channel, |split_layer| split_layer.channel(u7::from(key)));
+
+                if key as isize != transposed_key {
+                    diagnostics.warn(Warning::NoteOutOfMidiRange {
+                        note_name: note_name.clone(),
+                        layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                        seconds: seconds_note_on,
+                    })?;
+                }
+
+                if args.program_per_note {
+                    absolute_track_events.push(AbsoluteTrackEvent {
+                        track,
+                        source: notes_layer.midi_name(args.name_source),
+                        ticks: ticks_note_on,
+                        ticks_event_start: ticks_note_on,
+                        seconds: seconds_note_on,
+                        kind: TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::ProgramChange {
+                                program: program_map
+                                    .get(&channel)
+                                    .copied()
+                                    .unwrap_or(midi_bank_program(play_parameters).1),
+                            },
+                        },
+                    });
+                }
+
+                // Pitch bend is channel-wide, simultaneous microtonal notes of a
+                // layer bend each other
+                if args.microtonal {
+                    let value = point_value(point).unwrap_or_default();
+                    let semitones = value - value.round();
+
+                    absolute_track_events.push(AbsoluteTrackEvent {
+                        track,
+                        source: notes_layer.midi_name(args.name_source),
+                        ticks: ticks_note_on,
+                        ticks_event_start: ticks_note_on,
+                        seconds: seconds_note_on,
+                        kind: TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::PitchBend {
+                                bend: PitchBend::from_f64(semitones / MIDI_PITCH_BEND_RANGE),
+                            },
+                        },
+                    });
+                }
+
+                if let Some(note_pan) = note_pan {
+                    absolute_track_events.push(AbsoluteTrackEvent {
+                        track,
+                        source: notes_layer.midi_name(args.name_source),
+                        ticks: ticks_note_on,
+                        ticks_event_start: ticks_note_on,
+                        seconds: seconds_note_on,
+                        kind: TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::Controller {
+                                controller: u7::from(MIDI_CONTROLLER_PAN),
+                                value: midi_pan(args.output_pan(note_pan)),
+                            },
+                        },
+                    });
+                }
+
+                absolute_track_events.extend([
+                    // Note on event
+                    AbsoluteTrackEvent {
+                        track,
+                        source: notes_layer.midi_name(args.name_source),
+                        ticks: ticks_note_on,
+                        ticks_event_start: ticks_note_on,
+                        seconds: seconds_note_on,
+                        kind: TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::NoteOn {
+                                key: u7::from(key),
+                                vel: u7::from(velocity),
+                            },
+                        },
+                    },
+                    // Note off event
+                    AbsoluteTrackEvent {
+                        track,
+                        source: notes_layer.midi_name(args.name_source),
+                        ticks: ticks_note_off,
+                        ticks_event_start: ticks_note_on, // Not a typo
+                        seconds: seconds_note_off,
+                        kind: TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::NoteOff {
+                                key: u7::from(key),
+                                vel: u7::from(MIDI_VELOCITY_NONE),
+                            },
+                        },
+                    },
+                ]);
+
+                if let Some(release_trigger) = args.release_trigger {
+                    let ticks_trigger_off = match args.min_note_duration {
+                        Some(min_note_duration) => {
+                            Seconds(seconds_note_off.0 + min_note_duration / 1000.0).as_midi_ticks(
+                                tempo_map,
+                                args.midi_ticks_per_beat,
+                                args.tick_rounding,
+                            )
+                        }
+                        None => ticks_note_off + args.midi_ticks_per_beat.get() / 4,
+                    }
+                    .max(ticks_note_off + 1);
+
+                    absolute_track_events.extend([
+                        AbsoluteTrackEvent {
+                            track,
+                            source: notes_layer.midi_name(args.name_source),
+                            ticks: ticks_note_off,
+                            ticks_event_start: ticks_note_off,
+                            seconds: seconds_note_off,
+                            kind: TrackEventKind::Midi {
+                                channel: release_trigger.channel,
+                                message: MidiMessage::NoteOn {
+                                    key: release_trigger.key,
+                                    vel: u7::from(velocity),
+                                },
+                            },
+                        },
+                        AbsoluteTrackEvent {
+                            track,
+                            source: notes_layer.midi_name(args.name_source),
+                            ticks: ticks_trigger_off,
+                            ticks_event_start: ticks_note_off,
+                            seconds: tempo_map.seconds(
+                                ticks_trigger_off as f64 / args.midi_ticks_per_beat.get() as f64,
+                            ),
+                            kind: TrackEventKind::Midi {
+                                channel: release_trigger.channel,
+                                message: MidiMessage::NoteOff {
+                                    key: release_trigger.key,
+                                    vel: u7::from(MIDI_VELOCITY_NONE),
+                                },
+                            },
+                        },
+                    ]);
+                }
+            }
+
+            if model.is_dense() {
+                diagnostics.note(format!(
+                    "colour 3D plot layer '{}' exported as {} notes, such dense note data may overwhelm some MIDI receivers",
+                    notes_layer.midi_name(args.name_source).escape_default(),
+                    dataset.points.len()
+                ));
+            }
+
+            if imploded_note_count > 0 {
+                diagnostics.note(format!(
+                    "notes layer '{}' has {} imploded notes{}",
+                    notes_layer.midi_name(args.name_source).escape_default(),
+                    imploded_note_count,
+                    if args.drop_imploded { ", dropped" } else { "" }
+                ));
+            }
+
+            if !dropped_voices.is_empty() {
+                diagnostics.note(format!(
+                    "notes layer '{}' exceeded its maximum polyphony, {} notes dropped",
+                    notes_layer.midi_name(args.name_source).escape_default(),
+                    dropped_voices.len()
+                ));
+            }
+
+            if short_note_count > 0 {
+                diagnostics.note(format!(
+                    "notes layer '{}' has {} notes shorter than the minimum duration, {}",
+                    notes_layer.midi_name(args.name_source).escape_default(),
+                    short_note_count,
+                    match args.min_note_action {
+                        MinNoteAction::Drop => "dropped",
+                        MinNoteAction::Extend => "extended",
+                    }
+                ));
+            }
+        }
+
+        for &instants_layer in sv_instants_layers.iter() {
+            let (model, dataset) = sv_document.get_layer_model_and_dataset(instants_layer)?;
+
+            let play_parameters = sv_document
+                .get_play_parameters_by_id(instants_layer.model)
+                .expect("failed to find play parameters");
+
+            let key = args
+                .flat_drums
+                .unwrap_or_else(|| play_parameters.midi_drum_note());
+
+            for point in dataset.points.iter() {
+                let seconds_note_on = Seconds::new(point.frame, model.sample_rate);
+
+                let length_ticks = match args.drum_note_length {
+                    Some(drum_note_length) => drum_note_length.get() as usize,
+                    None => args.midi_ticks_per_beat.get() / 4, // Expand the zero-length instants into 1/32 MIDI notes
+                };
+
+                let ticks_note_on = seconds_note_on.as_midi_ticks(
+                    tempo_map,
+                    args.midi_ticks_per_beat,
+                    args.tick_rounding,
+                );
+                let ticks_note_off = ticks_note_on + length_ticks;
+                assert!(ticks_note_on <= ticks_note_off);
+
+                if ticks_note_on == ticks_note_off {
+                    diagnostics.warn(Warning::InsufficientResolution {
+                        note_name: None,
+                        layer_type: String::from("instants"),
+                        layer_name: instants_layer.midi_name(args.name_source).to_owned(),
+                        seconds: seconds_note_on,
+                    })?;
+                }
+
+                absolute_track_events.extend([
+                    // Note on event
+                    AbsoluteTrackEvent {
+                        track: drum_track,
+                        source: instants_layer.midi_name(args.name_source),
+                        ticks: ticks_note_on,
+                        ticks_event_start: ticks_note_on,
+                        seconds: seconds_note_on,
+                        kind: TrackEventKind::Midi {
+                            channel: u4::from(MIDI_DRUM_CHANNEL),
+                            message: MidiMessage::NoteOn {
+                                key,
+                                vel: u7::from(MIDI_VELOCITY_DEFAULT),
+                            },
+                        },
+                    },
+                    // Note off event
+                    AbsoluteTrackEvent {
+                        track: drum_track,
+                        source: instants_layer.midi_name(args.name_source),
+                        ticks: ticks_note_off,
+                        ticks_event_start: ticks_note_on, // Not a typo
+                        seconds: seconds_note_on,         // Instants are zero-length, this is okay.
+                        kind: TrackEventKind::Midi {
+                            channel: u4::from(MIDI_DRUM_CHANNEL),
+                            message: MidiMessage::NoteOff {
+                                key,
+                                vel: u7::from(MIDI_VELOCITY_NONE),
+                            },
+                        },
+                    },
+                ]);
+            }
+        }
+
+        if args.note_labels {
+            let mut note_labels = sv_notes_layers
+                .iter()
+                .flat_map(|&(_, notes_layer)| {
+                    let model = sv_document
+                        .get_model_by_id(notes_layer.model)
+                        .expect("notes layer doesn't have model specified");
+
+                    let dataset_id = model.dataset.expect("model doesn't have dataset specified");
+                    let dataset = sv_document
+                        .get_dataset_by_id(dataset_id)
+                        .expect("dataset doesn't exist");
+
+                    dataset
+                        .points
+                        .iter()
+                        .filter(|point| !point.label().is_empty())
+                        .map(move |point| {
+                            let seconds_label = Seconds::new(point.frame, model.sample_rate);
+                            let ticks_label = seconds_label.as_midi_ticks(
+                                tempo_map,
+                                args.midi_ticks_per_beat,
+                                args.tick_rounding,
+                            );
+
+                            (ticks_label, point.label(), seconds_label, notes_layer)
+                        })
+                })
+                .collect::<Vec<_>>();
+
+            // Simultaneous chord tones often carry the same label, emit it only once
+            note_labels.sort_by_key(|&(ticks_label, label, ..)| (ticks_label, label));
+            note_labels.dedup_by_key(|&mut (ticks_label, label, ..)| (ticks_label, label));
+
+            for (ticks_label, label, seconds_label, notes_layer) in note_labels.into_iter() {
+                if !label.is_ascii() {
+                    diagnostics.warn(Warning::NonAsciiLabel {
+                        label: label.to_owned(),
+                        layer_type: String::from("notes"),
+                        layer_name: notes_layer.midi_name(args.name_source).to_owned(),
+                        seconds: seconds_label,
+                    })?;
+                    diagnostics.note(
+                        "these text events may be mishandled by other music software".to_owned(),
+                    );
+                }
+
+                absolute_track_events.push(AbsoluteTrackEvent {
+                    track: conductor_track,
+                    source: notes_layer.midi_name(args.name_source),
+                    ticks: ticks_label,
+                    ticks_event_start: ticks_label,
+                    seconds: seconds_label,
+                    kind: TrackEventKind::Meta(args.note_label_meta.meta_message(label.as_bytes())),
+                });
+            }
+        }
+
+        for &text_layer in sv_text_layers.iter() {
+            // Lyrics are passed through literally, including the Soft Karaoke
+            // line and paragraph markers
+            let track = text_layer_track(text_layer);
+
+            let (model, dataset) = sv_document.get_layer_model_and_dataset(text_layer)?;
+
+            for point in dataset.points.iter() {
+                let seconds_text = Seconds::new(point.frame, model.sample_rate);
+
+                let ticks_text = seconds_text.as_midi_ticks(
+                    tempo_map,
+                    args.midi_ticks_per_beat,
+                    args.tick_rounding,
+                );
+
+                if !point.label().is_ascii() {
+                    diagnostics.warn(Warning::NonAsciiLabel {
+                        label: point.label().to_owned(),
+                        layer_type: String::from("text"),
+                        layer_name: text_layer.midi_name(args.name_source).to_owned(),
+                        seconds: seconds_text,
+                    })?;
+                    diagnostics.note(
+                        "these text events may be mishandled by other music software".to_owned(),
+                    );
+                }
+
+                match (point.duration, text_span_labels.get(point.label())) {
+                    (Some(duration), Some((label_start, label_end))) => {
+                        let seconds_text_end =
+                            Seconds::new(point.frame + duration, model.sample_rate);
+
+                        let ticks_text_end = seconds_text_end.as_midi_ticks(
+                            tempo_map,
+                            args.midi_ticks_per_beat,
+                            args.tick_rounding,
+                        );
+
+                        absolute_track_events.extend([
+                            AbsoluteTrackEvent {
+                                track,
+                                source: text_layer.midi_name(args.name_source),
+                                ticks: ticks_text,
+                                ticks_event_start: ticks_text,
+                                seconds: seconds_text,
+                                kind: TrackEventKind::Meta(MetaMessage::Text(
+                                    label_start.as_bytes(),
+                                )),
+                            },
+                            AbsoluteTrackEvent {
+                                track,
+                                source: text_layer.midi_name(args.name_source),
+                                ticks: ticks_text_end,
+                                ticks_event_start: ticks_text,
+                                seconds: seconds_text_end,
+                                kind: TrackEventKind::Meta(MetaMessage::Text(label_end.as_bytes())),
+                            },
+                        ]);
+                    }
+                    _ => {
+                        absolute_track_events.push(AbsoluteTrackEvent {
+                            track,
+                            source: text_layer.midi_name(args.name_source),
+                            ticks: ticks_text,
+                            ticks_event_start: ticks_text,
+                            seconds: seconds_text,
+                            kind: TrackEventKind::Meta(MetaMessage::Text(point.label().as_bytes())),
+                        });
+                    }
+                }
+            }
+        }
+
+        // Linear volume ramps between silence and the channel volume, the fades
+        // are aligned to the first NoteOn and the last NoteOff of the song
+        let note_seconds = absolute_track_events
+            .iter()
+            .filter(|event| event.kind.is_note_on() || event.kind.is_note_off())
+            .map(|event| event.seconds);
+
+        if let (Some(song_start), Some(song_end)) = (note_seconds.clone().min(), note_seconds.max())
+        {
+            for (layer_index, &(layer_channel, notes_layer)) in sv_notes_layers.iter().enumerate() {
+                let volume = layer_volumes[layer_index].as_int() as f64;
+                let channels = [
+                    Some(layer_channel),
+                    layer_splits[layer_index].map(|split_layer| split_layer.high_channel),
+                ];
+
+                let fade_in_steps = args.fade_in.into_iter().flat_map(|fade_in| {
+                    (0..=MIDI_FADE_STEPS).map(move |step| {
+                        let progress = step as f64 / MIDI_FADE_STEPS as f64;
+                        (song_start.0 + fade_in * progress, volume * progress)
+                    })
+                });
+
+                let fade_out_steps = args.fade_out.into_iter().flat_map(|fade_out| {
+                    (0..=MIDI_FADE_STEPS).map(move |step| {
+                        let progress = step as f64 / MIDI_FADE_STEPS as f64;
+                        (
+                            song_end.0 - fade_out * (1.0 - progress),
+                            volume * (1.0 - progress),
+                        )
+                    })
+                });
+
+                for (seconds, fade_volume) in fade_in_steps.chain(fade_out_steps) {
+                    let seconds_fade = Seconds(seconds.max(0.0));
+                    let ticks_fade = seconds_fade.as_midi_ticks(
+                        tempo_map,
+                        args.midi_ticks_per_beat,
+                        args.tick_rounding,
+                    );
+
+                    for channel in channels.into_iter().flatten() {
+                        absolute_track_events.push(AbsoluteTrackEvent {
+                            track: notes_layer_track(layer_index),
+                            source: notes_layer.midi_name(args.name_source),
+                            ticks: ticks_fade,
+                            ticks_event_start: ticks_fade,
+                            seconds: seconds_fade,
+                            kind: TrackEventKind::Midi {
+                                channel,
+                                message: MidiMessage::Controller {
+                                    controller: u7::from(MIDI_CONTROLLER_VOLUME),
+                                    value: u7::from(fade_volume.round() as u8),
+                                },
+                            },
+                        });
+                    }
+                }
+            }
+        }
+
+        if args.model_markers {
+            let mut model_ids = sv_notes_layers
+                .iter()
+                .map(|&(_, notes_layer)| notes_layer.model)
+                .chain(
+                    sv_instants_layers
+                        .iter()
+                        .map(|instants_layer| instants_layer.model),
+                )
+                .chain(sv_text_layers.iter().map(|text_layer| text_layer.model))
+                .collect::<Vec<_>>();
+
+            model_ids.sort_unstable();
+            model_ids.dedup();
+
+            for model_id in model_ids {
+                let model = sv_document
+                    .get_model_by_id(model_id)
+                    .expect("layer doesn't have model specified");
+
+                let seconds_marker = Seconds::new(model.start, model.sample_rate);
+                let ticks_marker = seconds_marker.as_midi_ticks(
+                    tempo_map,
+                    args.midi_ticks_per_beat,
+                    args.tick_rounding,
+                );
+
+                absolute_track_events.push(AbsoluteTrackEvent {
+                    track: conductor_track,
+                    source: &model.name,
+                    ticks: ticks_marker,
+                    ticks_event_start: ticks_marker,
+                    seconds: seconds_marker,
+                    kind: TrackEventKind::Meta(MetaMessage::Marker(model.name.as_bytes())),
+                });
+            }
+        }
+
+        // The initial tempo is part of the track setup
+        if !args.no_tempo {
+            for tempo_change in tempo_map.changes().iter().skip(1) {
+                let ticks_tempo = tempo_change.seconds.as_midi_ticks(
+                    tempo_map,
+                    args.midi_ticks_per_beat,
+                    args.tick_rounding,
+                );
+
+                absolute_track_events.push(AbsoluteTrackEvent {
+                    track: conductor_track,
+                    source: "tempo map",
+                    ticks: ticks_tempo,
+                    ticks_event_start: ticks_tempo,
+                    seconds: tempo_change.seconds,
+                    kind: TrackEventKind::Meta(MetaMessage::Tempo(midi_tempo(tempo_change.bpm))),
+                });
+            }
+        }
+
+        absolute_track_events.sort_by_key(
+            |&AbsoluteTrackEvent {
+                 ticks,
+                 ticks_event_start,
+                 kind,
+                 ..
+             }| {
+                // Events of the same type at the same position are ordered by
+                // channel and key, the remaining ties (meta events) keep their
+                // emission order, which is deterministic
+                (
+                    ticks,
+                    ticks_event_start,
+                    kind.event_type_priority(),
+                    kind.channel(),
+                    kind.note_key(),
+                )
+            },
+        );
+
+        // Identical channel events at the same tick, e.g. from duplicated
+        // layers sharing a channel, would trigger notes twice. A dropped
+        // NoteOn takes the next NoteOff of its key on the same track with it,
+        // keeping the tracks self-contained.
+        {
+            let mut tick = None;
+            let mut tick_events = HashSet::new();
+            let mut orphaned_note_offs: HashMap<_, usize> = HashMap::new();
+            let mut duplicate_count = 0;
+
+            absolute_track_events.retain(|event| {
+                let note = event
+                    .kind
+                    .channel()
+                    .zip(event.kind.note_key())
+                    .map(|(channel, key)| (event.track, channel, key));
+
+                if event.kind.is_note_off() {
+                    return match note.and_then(|note| orphaned_note_offs.get_mut(&note)) {
+                        Some(count) if *count > 0 => {
+                            *count -= 1;
+                            false
+                        }
+                        _ => true,
+                    };
+                }
+
+                if tick != Some(event.ticks) {
+                    tick = Some(event.ticks);
+                    tick_events.clear();
+                }
+
+                if event.kind.channel().is_none()
+                    || tick_events.insert((track_ports[event.track], event.kind))
+                {
+                    return true;
+                }
+
+                if let Some(note) = note {
+                    *orphaned_note_offs.entry(note).or_default() += 1;
+                }
+
+                if args.verbose {
+                    diagnostics.note(format!(
+                        "dropping duplicate event {}",
+                        describe_event(event)
+                    ));
+                }
+
+                duplicate_count += 1;
+                false
+            });
+
+            if duplicate_count > 0 {
+                diagnostics.note(format!(
+                    "{} duplicate simultaneous events dropped",
+                    duplicate_count
+                ));
+            }
+        }
+
+        // Full check of the sorted events before writing anything, catching
+        // sorting bugs and unpaired notes
+        if args.verify {
+            for window in absolute_track_events.windows(2) {
+                if window[0].ticks > window[1].ticks {
+                    return Err(ConversionError::InvariantViolation(format!(
+                        "event {} precedes event {}",
+                        describe_event(&window[0]),
+                        describe_event(&window[1])
+                    ))
+                    .into());
+                }
+            }
+
+            let mut sounding_notes: HashMap<_, Vec<&AbsoluteTrackEvent>> = HashMap::new();
+
+            for event in absolute_track_events.iter() {
+                let note = match (event.kind.channel(), event.kind.note_key()) {
+                    (Some(channel), Some(key)) => (track_ports[event.track], channel, key),
+                    _ => continue,
+                };
+
+                if event.kind.is_note_on() {
+                    sounding_notes.entry(note).or_default().push(event);
+                } else if sounding_notes.entry(note).or_default().pop().is_none() {
+                    return Err(ConversionError::InvariantViolation(format!(
+                        "event {} has no matching NoteOn",
+                        describe_event(event)
+                    ))
+                    .into());
+                }
+            }
+
+            if let Some(&event) = sounding_notes
+                .values()
+                .flatten()
+                .min_by_key(|event| event.ticks)
+            {
+                return Err(ConversionError::InvariantViolation(format!(
+                    "event {} has no matching NoteOff",
+                    describe_event(event)
+                ))
+                .into());
+            }
+        }
+
+        // Thinning continuous data, every controller, pitch bend and aftertouch
+        // stream is handled separately. The last value of a dense run is kept
+        // so the stream settles at the right value.
+        if let Some(cc_max_rate) = args.cc_max_rate {
+            let min_interval = 1.0 / cc_max_rate;
+
+            let stream_key = |kind: &TrackEventKind| match *kind {
+                TrackEventKind::Midi { channel, message } => match message {
+                    MidiMessage::Controller { controller, .. } => {
+                        Some((channel, 0, controller.as_int()))
+                    }
+                    MidiMessage::PitchBend { .. } => Some((channel, 1, 0)),
+                    MidiMessage::ChannelAftertouch { .. } => Some((channel, 2, 0)),
+                    MidiMessage::Aftertouch { key, .. } => Some((channel, 3, key.as_int())),
+                    _ => None,
+                },
+                _ => None,
+            };
+
+            let mut streams: HashMap<_, Vec<usize>> = HashMap::new();
+
+            for (event_index, event) in absolute_track_events.iter().enumerate() {
+                if let Some(key) = stream_key(&event.kind) {
+                    streams
+                        .entry((track_ports[event.track], key))
+                        .or_default()
+                        .push(event_index);
+                }
+            }
+
+            let mut dropped_events = HashSet::new();
+
+            for event_indices in streams.values() {
+                let mut last_kept_seconds: Option<f64> = None;
+
+                for (position, &event_index) in event_indices.iter().enumerate() {
+                    let seconds = absolute_track_events[event_index].seconds.0;
+
+                    let rate_allows = last_kept_seconds.is_none_or(|last_kept_seconds| {
+                        seconds - last_kept_seconds >= min_interval
+                    });
+
+                    let settles = event_indices.get(position + 1).is_none_or(|&next_index| {
+                        absolute_track_events[next_index].seconds.0 - seconds >= min_interval
+                    });
+
+                    if rate_allows || settles {
+                        last_kept_seconds = Some(seconds);
+                    } else {
+                        dropped_events.insert(event_index);
+                    }
+                }
+            }
+
+            if !dropped_events.is_empty() {
+                diagnostics.note(format!(
+                    "{} continuous controller events dropped by the rate limit",
+                    dropped_events.len()
+                ));
+            }
+
+            let mut event_index = 0;
+            absolute_track_events.retain(|_| {
+                event_index += 1;
+                !dropped_events.contains(&(event_index - 1))
+            });
+        }
+
+        // The channel setup values are taken into account too, per-note
+        // controllers often repeat them
+        if args.dedup_controllers {
+            let dropped_count =
+                dedup_controllers(&mut absolute_track_events, &midi_tracks, &track_ports);
+
+            if dropped_count > 0 {
+                diagnostics.note(format!(
+                    "{} redundant controller events dropped",
+                    dropped_count
+                ));
+            }
+        }
+
+        {
+            let mut current_polyphony = 0;
+            let mut already_warned = false;
+
+            for event in absolute_track_events.iter() {
+                if event.kind.is_note_on() {
+                    current_polyphony += 1;
+
+                    if (current_polyphony > MIDI_MAX_POLYPHONY) && !already_warned {
+                        diagnostics.warn(Warning::ExcessivePolyphony {
+                            seconds: event.seconds,
+                        })?;
+                        already_warned = true;
+                    }
+                }
+
+                if event.kind.is_note_off() {
+                    assert!(current_polyphony > 0);
+                    current_polyphony -= 1;
+
+                    if (current_polyphony <= MIDI_MAX_POLYPHONY) && already_warned {
+                        already_warned = false;
+                    }
+                }
+            }
+        }
+
+        {
+            let mut current_note_counts = HashMap::new();
+
+            for event in absolute_track_events.iter() {
+                let (port, channel, key) = match (event.kind.channel(), event.kind.note_key()) {
+                    (Some(channel), Some(key)) => (track_ports[event.track], channel, key),
+                    _ => continue,
+                };
+
+                if event.kind.is_note_on() {
+                    let note_count = current_note_counts.entry((port, channel, key)).or_insert(0);
+                    *note_count += 1;
+
+                    if *note_count >= 2 {
+                        diagnostics.warn(Warning::NoteOverlap {
+                            note_name: midi_note_name(
+                                key.as_int() as isize,
+                                args.note_octave_offset,
+                            ),
+                            seconds: event.seconds,
+                        })?;
+                    }
+                }
+
+                if event.kind.is_note_off() {
+                    let note_count = current_note_counts
+                        .get_mut(&(port, channel, key))
+                        .expect("failed to get note count");
+
+                    assert!(*note_count > 0);
+                    *note_count -= 1;
+
+                    if *note_count == 0 {
+                        current_note_counts.remove(&(port, channel, key));
+                    }
+                }
+            }
+        }
+
+        // Cheap guard against event manipulations, the full pairing is checked
+        // by --verify
+        check_note_events(&absolute_track_events)?;
+
+        let setup_event_counts = midi_tracks
+            .iter()
+            .map(|midi_track| midi_track.len())
+            .collect::<Vec<_>>();
+
+        let ticks_trimmed = delta_encode(
+            &mut midi_tracks,
+            &absolute_track_events,
+            args.trim_leading_silence,
+        )?;
+
+        // Pairing the notes the same way as the verification above, for
+        // comparing conversions and drawing the piano roll
+        {
+            let mut sounding_notes: HashMap<_, Vec<&AbsoluteTrackEvent>> = HashMap::new();
+            let mut piano_roll_notes = Vec::new();
+
+            for event in absolute_track_events.iter() {
+                match event.kind {
+                    TrackEventKind::Midi {
+                        channel,
+                        message: MidiMessage::NoteOn { key, .. },
+                    } => sounding_notes
+                        .entry((track_ports[event.track], channel, key))
+                        .or_default()
+                        .push(event),
+                    TrackEventKind::Midi {
+                        channel,
+                        message: MidiMessage::NoteOff { key, .. },
+                    } => {
+                        if let Some(note_on) = sounding_notes
+                            .entry((track_ports[event.track], channel, key))
+                            .or_default()
+                            .pop()
+                        {
+                            exported_events
+                                .notes
+                                .entry(note_on.source.to_owned())
+                                .or_default()
+                                .push(ExportedNote {
+                                    key: key.as_int(),
+                                    start: note_on.seconds,
+                                    end: event.seconds,
+                                });
+
+                            piano_roll_notes.push(PianoRollNote {
+                                key: key.as_int(),
+                                start_ticks: note_on.ticks - ticks_trimmed,
+                                end_ticks: event.ticks - ticks_trimmed,
+                            });
+                        }
+                    }
+                    TrackEventKind::Meta(
+                        MetaMessage::Text(data)
+                        | MetaMessage::Lyric(data)
+                        | MetaMessage::Marker(data),
+                    ) => exported_events
+                        .texts
+                        .entry(event.source.to_owned())
+                        .or_default()
+                        .push(ExportedText {
+                            seconds: event.seconds,
+                            text: String::from_utf8_lossy(data).into_owned(),
+                        }),
+                    _ => {}
+                }
+            }
+
+            if args.piano_roll {
+                write_piano_roll(
+                    &mut io::stderr().lock(),
+                    &piano_roll_notes,
+                    args.note_octave_offset,
+                )?;
+            }
+        }
+
+        if args.panic_at_end {
+            let mut note_channels = absolute_track_events
+                .iter()
+                .filter(|event| event.kind.is_note_on())
+                .filter_map(|event| Some((event.track, event.kind.channel()?)))
+                .collect::<Vec<_>>();
+
+            note_channels.sort_unstable();
+            note_channels.dedup();
+
+            for (track, channel) in note_channels {
+                for controller in [MIDI_CONTROLLER_ALL_NOTES_OFF, MIDI_CONTROLLER_ALL_SOUND_OFF] {
+                    midi_tracks[track].push(TrackEvent {
+                        delta: u28::from(0),
+                        kind: TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::Controller {
+                                controller: u7::from(controller),
+                                value: u7::from(0),
+                            },
+                        },
+                    });
+                }
+            }
+        }
+
+        for midi_track in midi_tracks.iter_mut() {
+            midi_track.push(TrackEvent {
+                delta: u28::from(0),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            });
+        }
+
+        // Exactly one EndOfTrack closing every track, even the empty ones
+        for (track, midi_track) in midi_tracks.iter().enumerate() {
+            let end_of_track_count = midi_track
+                .iter()
+                .filter(|track_event| {
+                    matches!(
+                        track_event.kind,
+                        TrackEventKind::Meta(MetaMessage::EndOfTrack)
+                    )
+                })
+                .count();
+
+            if end_of_track_count != 1 {
+                return Err(ConversionError::InvariantViolation(format!(
+                    "track {} has {} EndOfTrack events",
+                    track, end_of_track_count
+                ))
+                .into());
+            }
+        }
+
+        // Setup events are sent at the start, the converted events follow in
+        // their final order
+        if args.output_format == OutputFormat::Osc {
+            let mut note_durations = HashMap::new();
+            let mut sounding_notes: HashMap<_, Vec<usize>> = HashMap::new();
+
+            for (index, event) in absolute_track_events.iter().enumerate() {
+                let note = match (event.kind.channel(), event.kind.note_key()) {
+                    (Some(channel), Some(key)) => (track_ports[event.track], channel, key),
+                    _ => continue,
+                };
+
+                if event.kind.is_note_on() {
+                    sounding_notes.entry(note).or_default().push(index);
+                } else if let Some(note_on_index) = sounding_notes.entry(note).or_default().pop() {
+                    note_durations.insert(
+                        note_on_index,
+                        event.seconds.0 - absolute_track_events[note_on_index].seconds.0,
+                    );
+                }
+            }
+
+            for (track, midi_track) in midi_tracks.iter().enumerate() {
+                for track_event in midi_track[..setup_event_counts[track]].iter() {
+                    osc_messages.extend(osc_message(0.0, &track_event.kind, None));
+                }
+            }
+
+            for (index, event) in absolute_track_events.iter().enumerate() {
+                osc_messages.extend(osc_message(
+                    event.seconds.0,
+                    &event.kind,
+                    note_durations.get(&index).copied(),
+                ));
+            }
+        }
+
+        // Listing the tracks as written, the converted events of each track
+        // follow its setup events in the same order as in the sorted list
+        if let Some(print_events_path) = &args.print_events {
+            let mut event_dump_lines = Vec::new();
+
+            for (track, midi_track) in midi_tracks.iter().enumerate() {
+                let mut track_events = absolute_track_events
+                    .iter()
+                    .filter(|event| event.track == track);
+                let mut ticks = ticks_trimmed;
+
+                for (index, track_event) in midi_track.iter().enumerate() {
+                    ticks += track_event.delta.as_int() as usize;
+
+                    let source_event = if index >= setup_event_counts[track] {
+                        track_events.next()
+                    } else {
+                        None
+                    };
+
+                    event_dump_lines.push(EventDumpLine {
+                        track,
+                        ticks,
+                        delta: track_event.delta.as_int() as usize,
+                        seconds: match source_event {
+                            Some(event) => event.seconds,
+                            None => tempo_map
+                                .seconds(ticks as f64 / args.midi_ticks_per_beat.get() as f64),
+                        },
+                        kind: track_event.kind,
+                        source: source_event.map(|event| event.source),
+                    });
+                }
+            }
+
+            if print_events_path == Path::new("-") {
+                write_event_dump(
+                    &mut io::stdout().lock(),
+                    &event_dump_lines,
+                    args.print_events_format,
+                    args.note_octave_offset,
+                )?;
+            } else {
+                fs::File::create(print_events_path)
+                    .and_then(|mut print_events_file| {
+                        write_event_dump(
+                            &mut print_events_file,
+                            &event_dump_lines,
+                            args.print_events_format,
+                            args.note_octave_offset,
+                        )
+                    })
+                    .map_err(|err| Sv2MidError::Write(err).in_path(print_events_path))?;
+            }
+        }
+    }
+
+    midi_document.tracks = midi_tracks;
+
+    if args.dry_run {
+        for &(port, channel, notes_layer) in notes_layer_channels.iter() {
+            diagnostics.note(format!(
+                "notes layer '{}' assigned to MIDI channel {}{}",
+                notes_layer.midi_name(args.name_source).escape_default(),
+                channel,
+                port_suffix(args, port)
+            ));
+        }
+    }
+
+    let output_data = if args.output_format == OutputFormat::Osc {
+        osc_messages
+            .iter()
+            .map(|osc_message| format!("{}\n", osc_message))
+            .collect::<String>()
+            .into_bytes()
+    } else {
+        let mut smf_data = Vec::new();
+        midi_document.write_std(&mut smf_data).map_err(|err| {
+            ConversionError::InvariantViolation(format!("MIDI encoding failed: {}", err))
+        })?;
+
+        let output_data = match container {
+            Container::Smf => smf_data,
+            Container::Rmid => wrap_rmid(&smf_data),
+        };
+
+        if args.verify {
+            let written_smf_data = unwrap_rmid(&output_data).unwrap_or(&output_data);
+
+            let written_midi_document = Smf::parse(written_smf_data).map_err(|err| {
+                ConversionError::InvariantViolation(format!(
+                    "the written MIDI data cannot be read back: {}",
+                    err
+                ))
+            })?;
+
+            if written_midi_document != midi_document {
+                return Err(ConversionError::InvariantViolation(String::from(
+                    "the written MIDI data differs from the converted events",
+                ))
+                .into());
+            }
+        }
+
+        output_data
+    };
+
+    Ok((output_data, exported_events))
+}
+
+/// Suffix naming the port of a channel in multi-port files.
+fn port_suffix(args: &Args, port: u8) -> String {
+    if args.multi_port {
+        format!(" on port {}", port)
+    } else {
+        String::new()
+    }
+}
+
+/// Suffix of the verbose layer lines noting that the name of the layer comes
+/// from the other attribute than the one chosen by `--name-source`.
+fn name_fallback_suffix(layer: &SvLayer, name_source: NameSource) -> String {
+    layer
+        .midi_name_fallback(name_source)
+        .map_or_else(String::new, |attribute| {
+            format!(", named after its {} attribute", attribute)
+        })
+}
+
+/// Finds the notes layer a text layer belongs to by the first word of their
+/// names, preferring the longest common prefix. Ties go to the first layer.
+fn matching_notes_layer(text_layer_name: &str, notes_layer_names: &[&str]) -> Option<usize> {
+    let first_word = |layer_name: &str| layer_name.split_whitespace().next().map(str::to_owned);
+
+    let text_layer_word = first_word(text_layer_name)?;
+
+    notes_layer_names
+        .iter()
+        .enumerate()
+        .filter(|&(_, &notes_layer_name)| {
+            first_word(notes_layer_name).as_ref() == Some(&text_layer_word)
+        })
+        .max_by_key(|&(layer_index, &notes_layer_name)| {
+            let common_prefix_length = text_layer_name
+                .chars()
+                .zip(notes_layer_name.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            (common_prefix_length, Reverse(layer_index))
+        })
+        .map(|(layer_index, _)| layer_index)
+}
+
+/// Checks that the distinct channels used on a port are valid MIDI channels,
+/// and that there are not more of them than a port has.
+fn check_port_channels(channels: &BTreeSet<u8>) -> Result<(), ConversionError> {
+    if (channels.len() > 16) || channels.iter().any(|&channel| channel > 15) {
+        return Err(ConversionError::InvalidChannels(
+            channels.iter().copied().collect(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a plain project with a notes layer playing a single C4 at the
+    /// given frame for each name, and a text layer with a single label for
+    /// each name.
+    fn project(notes_layers: &[(&str, usize)], text_layers: &[(&str, &str)]) -> Vec<u8> {
+        project_with_pan(notes_layers, text_layers, 0.5)
+    }
+
+    /// Like `project`, with the given pan for every notes layer.
+    fn project_with_pan(
+        notes_layers: &[(&str, usize)],
+        text_layers: &[(&str, &str)],
+        pan: f64,
+    ) -> Vec<u8> {
+        let mut data = String::new();
+        let mut layers = String::new();
+
+        for (index, &(layer_name, frame)) in notes_layers.iter().enumerate() {
+            let id = 100 + index * 3;
+
+            data += &format!(
+                r#"<model id="{}" name="{}" sampleRate="44100" start="0" end="441000" type="sparse" dimensions="3" resolution="1" dataset="{}" subtype="note"/>
+                <playparameters mute="false" pan="{}" gain="1" clipId="piano" model="{}"/>
+                <dataset id="{}" dimensions="3"><point frame="{}" value="60" duration="22050" label=""/></dataset>"#,
+                id,
+                layer_name,
+                id + 1,
+                pan,
+                id,
+                id + 1,
+                frame
+            );
+            layers += &format!(
+                r#"<layer id="{}" type="notes" name="{}" model="{}"/>"#,
+                id + 2,
+                layer_name,
+                id
+            );
+        }
+
+        for (index, &(layer_name, label)) in text_layers.iter().enumerate() {
+            let id = 500 + index * 3;
+
+            data += &format!(
+                r#"<model id="{}" name="{}" sampleRate="44100" start="0" end="441000" type="sparse" dimensions="2" resolution="1" dataset="{}" subtype="text"/>
+                <dataset id="{}" dimensions="2"><point frame="44100" height="0.5" label="{}"/></dataset>"#,
+                id,
+                layer_name,
+                id + 1,
+                id + 1,
+                label
+            );
+            layers += &format!(
+                r#"<layer id="{}" type="text" name="{}" model="{}"/>"#,
+                id + 2,
+                layer_name,
+                id
+            );
+        }
+
+        format!("<sv><data>{}{}</data></sv>", data, layers).into_bytes()
+    }
+
+    fn tracks(midi_data: &[u8]) -> Vec<Vec<TrackEvent<'_>>> {
+        Smf::parse(midi_data).unwrap().tracks
+    }
+
+    fn text_tracks(midi_data: &[u8]) -> Vec<(usize, String)> {
+        tracks(midi_data)
+            .iter()
+            .enumerate()
+            .flat_map(|(track, midi_track)| {
+                midi_track
+                    .iter()
+                    .filter_map(move |track_event| match track_event.kind {
+                        TrackEventKind::Meta(MetaMessage::Text(text)) => {
+                            Some((track, String::from_utf8_lossy(text).into_owned()))
+                        }
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matching_notes_layer_by_first_word() {
+        let notes_layer_names = ["Bass", "Melody", "Melody notes"];
+
+        assert_eq!(
+            matching_notes_layer("Melody lyrics", &notes_layer_names),
+            Some(2)
+        );
+        assert_eq!(
+            matching_notes_layer("Bass line", &notes_layer_names),
+            Some(0)
+        );
+        assert_eq!(matching_notes_layer("Chords", &notes_layer_names), None);
+        assert_eq!(matching_notes_layer("", &notes_layer_names), None);
+    }
+
+    #[test]
+    fn text_layers_placed_onto_notes_tracks() {
+        let input = project(
+            &[("Melody notes", 44100), ("Bass notes", 44100)],
+            &[("Melody lyrics", "la"), ("Chords", "Am")],
+        );
+
+        let midi_data = convert_bytes(&input, &["-q", "--midi-format", "1"]).unwrap();
+        assert_eq!(
+            text_tracks(&midi_data),
+            [(0, String::from("Am")), (1, String::from("la"))]
+        );
+
+        let midi_data = convert_bytes(
+            &input,
+            &[
+                "-q",
+                "--midi-format",
+                "1",
+                "--text-track",
+                "Melody lyrics=Bass notes",
+                "--text-track",
+                "Chords=Bass notes",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            text_tracks(&midi_data),
+            [(2, String::from("la")), (2, String::from("Am"))]
+        );
+
+        let midi_data = convert_bytes(&input, &["-q"]).unwrap();
+        assert_eq!(
+            text_tracks(&midi_data),
+            [(0, String::from("la")), (0, String::from("Am"))]
+        );
+    }
+
+    #[test]
+    fn channel_over_allocation_rejected() {
+        assert!(check_port_channels(&(0..16).collect()).is_ok());
+        assert!(check_port_channels(&BTreeSet::new()).is_ok());
+
+        assert!(matches!(
+            check_port_channels(&(0..17).collect()),
+            Err(ConversionError::InvalidChannels(channels)) if channels.len() == 17
+        ));
+        assert!(matches!(
+            check_port_channels(&[0, 16].into_iter().collect()),
+            Err(ConversionError::InvalidChannels(channels)) if channels == [0, 16]
+        ));
+    }
+
+    #[test]
+    fn shared_channels_only_warned_about() {
+        let layer_names = ["A", "B", "C", "D", "E", "F", "G", "H"];
+        let input = project(&layer_names.map(|layer_name| (layer_name, 44100)), &[]);
+
+        // All fifteen notes channels taken by the split layers, the last one
+        // sharing the first channel, and the release trigger sharing another
+        let split_layers = layer_names
+            .iter()
+            .zip([
+                (0, 1),
+                (2, 3),
+                (4, 5),
+                (6, 7),
+                (8, 10),
+                (11, 12),
+                (13, 14),
+                (15, 0),
+            ])
+            .map(|(layer_name, (low_channel, high_channel))| {
+                format!("{}:60:{}:{}", layer_name, low_channel, high_channel)
+            })
+            .collect::<Vec<_>>();
+        let mut options = vec!["-q", "--release-trigger", "24:1"];
+        for split_layer in split_layers.iter() {
+            options.extend(["--split-layer", split_layer]);
+        }
+
+        let (_, warnings) = convert_bytes_with_warnings(&input, &options).unwrap();
+        assert!(warnings
+            .iter()
+            .any(|warning| matches!(warning, Warning::SharedChannel { channel: 0, .. })));
+        assert!(warnings.iter().any(|warning| matches!(
+            warning,
+            Warning::ReleaseTriggerChannelShared { channel: 1, .. }
+        )));
+    }
+
+    #[test]
+    fn multi_port_layers_beyond_the_notes_channels() {
+        let layer_names = (0..17)
+            .map(|layer_index| format!("Layer {}", layer_index))
+            .collect::<Vec<_>>();
+        let input = project(
+            &layer_names
+                .iter()
+                .map(|layer_name| (layer_name.as_str(), 44100))
+                .collect::<Vec<_>>(),
+            &[],
+        );
+
+        let (midi_data, warnings) = convert_bytes_with_warnings(&input, &["-q"]).unwrap();
+        assert_eq!(
+            tracks(&midi_data)[0]
+                .iter()
+                .filter(|track_event| track_event.kind.is_note_on())
+                .count(),
+            15
+        );
+        assert!(warnings
+            .iter()
+            .any(|warning| matches!(warning, Warning::TooManyNotesLayers)));
+
+        let (midi_data, warnings) =
+            convert_bytes_with_warnings(&input, &["-q", "--multi-port"]).unwrap();
+        assert!(warnings.is_empty());
+
+        let midi_tracks = tracks(&midi_data);
+        assert_eq!(midi_tracks.len(), 18);
+
+        let port_and_channel = |midi_track: &[TrackEvent]| {
+            let port = midi_track
+                .iter()
+                .find_map(|track_event| match track_event.kind {
+                    TrackEventKind::Meta(MetaMessage::MidiPort(port)) => Some(port.as_int()),
+                    _ => None,
+                });
+            let channel = midi_track
+                .iter()
+                .find(|track_event| track_event.kind.is_note_on())
+                .and_then(|track_event| track_event.kind.channel())
+                .map(|channel| channel.as_int());
+
+            (port, channel)
+        };
+
+        assert_eq!(port_and_channel(&midi_tracks[0]), (Some(0), None));
+        assert_eq!(port_and_channel(&midi_tracks[1]), (Some(0), Some(0)));
+        assert_eq!(port_and_channel(&midi_tracks[15]), (Some(0), Some(15)));
+        assert_eq!(port_and_channel(&midi_tracks[16]), (Some(1), Some(0)));
+        assert_eq!(port_and_channel(&midi_tracks[17]), (Some(1), Some(1)));
+
+        assert!(matches!(
+            convert_bytes(&input, &["--multi-port", "--midi-format", "0"]),
+            Err(Sv2MidError::Options(_))
+        ));
+    }
+
+    #[test]
+    fn inverted_pan_mirrors_the_layer_pan() {
+        let channel_pan = |pan: f64, options: &[&str]| {
+            let midi_data =
+                convert_bytes(&project_with_pan(&[("Melody", 44100)], &[], pan), options).unwrap();
+
+            tracks(&midi_data)[0]
+                .iter()
+                .find_map(|track_event| match track_event.kind {
+                    TrackEventKind::Midi {
+                        message: MidiMessage::Controller { controller, value },
+                        ..
+                    } if controller == MIDI_CONTROLLER_PAN => Some(value.as_int()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        assert_eq!(
+            channel_pan(0.3, &["-q", "--invert-pan"]),
+            channel_pan(-0.3, &["-q"])
+        );
+        assert_eq!(
+            channel_pan(-0.3, &["-q", "--invert-pan"]),
+            channel_pan(0.3, &["-q"])
+        );
+        assert_ne!(channel_pan(0.3, &["-q"]), channel_pan(-0.3, &["-q"]));
+    }
+
+    /// Delta times of the NoteOn and NoteOff events of the first track.
+    fn note_deltas(midi_data: &[u8]) -> Vec<u32> {
+        tracks(midi_data)[0]
+            .iter()
+            .filter(|track_event| track_event.kind.is_note_on() || track_event.kind.is_note_off())
+            .map(|track_event| track_event.delta.as_int())
+            .collect()
+    }
+
+    #[test]
+    fn leading_silence_trimmed() {
+        let input = project(&[("Melody", 44100)], &[]);
+
+        let midi_data = convert_bytes(&input, &["-q"]).unwrap();
+        assert_eq!(note_deltas(&midi_data), [2048, 1024]);
+
+        let midi_data = convert_bytes(&input, &["-q", "-s"]).unwrap();
+        assert_eq!(note_deltas(&midi_data), [0, 1024]);
+    }
+
+    #[test]
+    fn empty_project_tracks_ended_once() {
+        let input = project(&[], &[]);
+
+        assert!(convert_bytes(&input, &["-q"]).is_err());
+
+        for midi_format in ["0", "1"] {
+            let midi_data = convert_bytes(
+                &input,
+                &["-q", "--allow-empty", "--midi-format", midi_format],
+            )
+            .unwrap();
+            let midi_tracks = tracks(&midi_data);
+
+            assert!(!midi_tracks.is_empty());
+            for midi_track in midi_tracks {
+                assert_eq!(
+                    midi_track
+                        .iter()
+                        .filter(|track_event| track_event.kind
+                            == TrackEventKind::Meta(MetaMessage::EndOfTrack))
+                        .count(),
+                    1
+                );
+                assert_eq!(
+                    midi_track.last().map(|track_event| track_event.kind),
+                    Some(TrackEventKind::Meta(MetaMessage::EndOfTrack))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn setup_events_precede_notes_at_zero() {
+        let input = project(&[("Melody", 0)], &[]);
+
+        for options in [
+            &["-q"][..],
+            &["-q", "-s"],
+            &["-q", "--midi-format", "1"],
+            &["-q", "--reset-controllers"],
+            &["-q", "--program-per-note"],
+        ] {
+            let midi_data = convert_bytes(&input, options).unwrap();
+            let mut checked_track_count = 0;
+
+            for midi_track in tracks(&midi_data) {
+                let kinds = midi_track
+                    .iter()
+                    .map(|track_event| track_event.kind)
+                    .collect::<Vec<_>>();
+                let first_note_on = kinds.iter().position(|kind| kind.is_note_on());
+                let last_setup = kinds
+                    .iter()
+                    .rposition(|kind| kind.is_program_change() || kind.is_controller());
+
+                if let (Some(first_note_on), Some(last_setup)) = (first_note_on, last_setup) {
+                    assert!(last_setup < first_note_on, "{:?}: {:?}", options, kinds);
+                    assert_eq!(midi_track[first_note_on].delta.as_int(), 0);
+                    checked_track_count += 1;
+                }
+            }
+
+            assert_eq!(checked_track_count, 1);
+        }
+    }
+}
@@ -1,9 +1,39 @@
-use midly::{MidiMessage, TrackEventKind};
+use midly::num::{u4, u7};
+use midly::{MetaMessage, MidiMessage, TrackEventKind};
 
+/// Event type predicates and accessors of MIDI events, also exported for
+/// inspecting the output of `convert_bytes`.
 pub trait TrackEventKindExt {
     fn is_note_on(&self) -> bool;
 
     fn is_note_off(&self) -> bool;
+
+    fn is_program_change(&self) -> bool;
+
+    fn is_controller(&self) -> bool;
+
+    fn is_pitch_bend(&self) -> bool;
+
+    fn is_meta(&self) -> bool;
+
+    fn is_sysex(&self) -> bool;
+
+    /// Key of NoteOn and NoteOff events.
+    fn note_key(&self) -> Option<u7>;
+
+    /// Velocity of NoteOn and NoteOff events.
+    fn velocity(&self) -> Option<u7>;
+
+    /// Channel of channel voice messages.
+    fn channel(&self) -> Option<u4>;
+
+    /// Distinct ordering of every event type, for sorting simultaneous events.
+    /// Meta events other than text come first, controllers (bank selects) and
+    /// program changes precede the notes they apply to, and text events close
+    /// the tick. NoteOn precedes NoteOff so zero-length notes are not left
+    /// hanging, NoteOff events of earlier notes are ordered ahead by their
+    /// start position when sorting.
+    fn event_type_priority(&self) -> u32;
 }
 
 impl TrackEventKindExt for TrackEventKind<'_> {
@@ -26,4 +56,87 @@ impl TrackEventKindExt for TrackEventKind<'_> {
             }
         )
     }
+
+    fn is_program_change(&self) -> bool {
+        matches!(
+            self,
+            TrackEventKind::Midi {
+                message: MidiMessage::ProgramChange { .. },
+                ..
+            }
+        )
+    }
+
+    fn is_controller(&self) -> bool {
+        matches!(
+            self,
+            TrackEventKind::Midi {
+                message: MidiMessage::Controller { .. },
+                ..
+            }
+        )
+    }
+
+    fn is_pitch_bend(&self) -> bool {
+        matches!(
+            self,
+            TrackEventKind::Midi {
+                message: MidiMessage::PitchBend { .. },
+                ..
+            }
+        )
+    }
+
+    fn is_meta(&self) -> bool {
+        matches!(self, TrackEventKind::Meta(..))
+    }
+
+    fn is_sysex(&self) -> bool {
+        matches!(self, TrackEventKind::SysEx(..) | TrackEventKind::Escape(..))
+    }
+
+    fn note_key(&self) -> Option<u7> {
+        match self {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. },
+                ..
+            } => Some(*key),
+            _ => None,
+        }
+    }
+
+    fn velocity(&self) -> Option<u7> {
+        match self {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { vel, .. } | MidiMessage::NoteOff { vel, .. },
+                ..
+            } => Some(*vel),
+            _ => None,
+        }
+    }
+
+    fn channel(&self) -> Option<u4> {
+        match self {
+            TrackEventKind::Midi { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+
+    fn event_type_priority(&self) -> u32 {
+        match self {
+            TrackEventKind::Meta(MetaMessage::Text(..) | MetaMessage::Lyric(..)) => 10,
+            TrackEventKind::Meta(..) => 0,
+            TrackEventKind::SysEx(..) => 1,
+            TrackEventKind::Escape(..) => 2,
+            TrackEventKind::Midi { message, .. } => match message {
+                MidiMessage::Controller { .. } => 3,
+                MidiMessage::ProgramChange { .. } => 4,
+                MidiMessage::ChannelAftertouch { .. } => 5,
+                MidiMessage::PitchBend { .. } => 6,
+                MidiMessage::NoteOn { .. } => 7,
+                MidiMessage::Aftertouch { .. } => 8,
+                MidiMessage::NoteOff { .. } => 9,
+            },
+        }
+    }
 }
@@ -0,0 +1,54 @@
+use clap::ArgEnum;
+use midly::{MetaMessage, MidiMessage, TrackEventKind};
+
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+pub enum OutputFormat {
+    /// Standard MIDI file
+    Midi,
+
+    /// Text dump of timestamped OSC messages
+    Osc,
+}
+
+/// OSC address and arguments of an event, one message per line prefixed
+/// with the timestamp in seconds. Notes are sent as a single message with
+/// their duration, NoteOff events and events without an OSC counterpart
+/// return `None`.
+pub fn osc_message(
+    seconds: f64,
+    kind: &TrackEventKind,
+    note_duration: Option<f64>,
+) -> Option<String> {
+    let text = |data: &[u8]| format!("\"{}\"", String::from_utf8_lossy(data).escape_default());
+
+    let (address, arguments) = match kind {
+        TrackEventKind::Midi { channel, message } => match message {
+            MidiMessage::NoteOn { key, vel } => (
+                "/note",
+                format!(
+                    "{} {} {} {:.6}",
+                    channel,
+                    key,
+                    vel,
+                    note_duration.unwrap_or_default()
+                ),
+            ),
+            MidiMessage::Controller { controller, value } => {
+                ("/cc", format!("{} {} {}", channel, controller, value))
+            }
+            MidiMessage::ProgramChange { program } => {
+                ("/program", format!("{} {}", channel, program))
+            }
+            MidiMessage::PitchBend { bend } => {
+                ("/pitchbend", format!("{} {}", channel, bend.as_int()))
+            }
+            _ => return None,
+        },
+        TrackEventKind::Meta(MetaMessage::Text(data)) => ("/text", text(data)),
+        TrackEventKind::Meta(MetaMessage::Lyric(data)) => ("/lyric", text(data)),
+        TrackEventKind::Meta(MetaMessage::Marker(data)) => ("/marker", text(data)),
+        _ => return None,
+    };
+
+    Some(format!("{:.6} {} {}", seconds, address, arguments))
+}
@@ -0,0 +1,65 @@
+use std::io::{self, Write};
+
+use crate::utils::midi_note_name;
+
+/// Width of the piano roll in characters, including the note names.
+const PIANO_ROLL_WIDTH: usize = 80;
+
+/// A converted note, positioned in MIDI ticks.
+pub struct PianoRollNote {
+    pub key: u8,
+    pub start_ticks: usize,
+    pub end_ticks: usize,
+}
+
+/// Draws the notes as a piano roll, one row per key from the highest note
+/// down to the lowest one. The time axis starts at tick 0 and is compressed
+/// to fit into 80 columns, every note occupies at least one column.
+pub fn write_piano_roll(
+    writer: &mut dyn Write,
+    notes: &[PianoRollNote],
+    note_octave_offset: i8,
+) -> io::Result<()> {
+    let (min_key, max_key) = match (
+        notes.iter().map(|note| note.key).min(),
+        notes.iter().map(|note| note.key).max(),
+    ) {
+        (Some(min_key), Some(max_key)) => (min_key, max_key),
+        _ => return writeln!(writer, "piano roll: no notes"),
+    };
+
+    let label_width = (min_key..=max_key)
+        .map(|key| midi_note_name(key as isize, note_octave_offset).len())
+        .max()
+        .unwrap_or(0);
+    let column_count = PIANO_ROLL_WIDTH - label_width - 1;
+
+    let end_ticks = notes.iter().map(|note| note.end_ticks).max().unwrap_or(0);
+    let ticks_per_column = end_ticks.div_ceil(column_count).max(1);
+
+    writeln!(writer, "piano roll: {} ticks per column", ticks_per_column)?;
+
+    for key in (min_key..=max_key).rev() {
+        let mut row = vec![' '; column_count];
+
+        for note in notes.iter().filter(|note| note.key == key) {
+            let start_column = (note.start_ticks / ticks_per_column).min(column_count - 1);
+            let end_column = note
+                .end_ticks
+                .div_ceil(ticks_per_column)
+                .clamp(start_column + 1, column_count);
+
+            row[start_column..end_column].fill('─');
+        }
+
+        writeln!(
+            writer,
+            "{:>label_width$}|{}",
+            midi_note_name(key as isize, note_octave_offset),
+            row.into_iter().collect::<String>().trim_end(),
+            label_width = label_width
+        )?;
+    }
+
+    Ok(())
+}
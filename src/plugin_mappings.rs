@@ -0,0 +1,84 @@
+use midly::num::u7;
+
+use crate::sv_model::SvPlayParameters;
+
+/// Instrument plugins and their programs known to correspond to General MIDI
+/// sounds: (plugin identifier, program name, bank select MSB, program number).
+const PLUGIN_PROGRAM_MAP: &[(&str, &str, u8, u8)] = &[
+    // Built-in sample player of Sonic Visualiser, programs named after the clips
+    ("dssi:_builtin:sample_player", "piano", 0, 0),
+    ("dssi:_builtin:sample_player", "elecpiano", 0, 5),
+    ("dssi:_builtin:sample_player", "organ", 0, 17),
+    ("dssi:_builtin:sample_player", "beep", 0, 80),
+    // FluidSynth with a GM2 sound font, variation banks above 0
+    (
+        "dssi:fluidsynth-dssi.so:FluidSynth-DSSI",
+        "Acoustic Grand Piano",
+        0,
+        0,
+    ),
+    (
+        "dssi:fluidsynth-dssi.so:FluidSynth-DSSI",
+        "Electric Piano 1",
+        0,
+        4,
+    ),
+    (
+        "dssi:fluidsynth-dssi.so:FluidSynth-DSSI",
+        "Detuned EP 1",
+        1,
+        4,
+    ),
+    (
+        "dssi:fluidsynth-dssi.so:FluidSynth-DSSI",
+        "Drawbar Organ",
+        0,
+        16,
+    ),
+    (
+        "dssi:fluidsynth-dssi.so:FluidSynth-DSSI",
+        "Detuned Organ 1",
+        1,
+        16,
+    ),
+    (
+        "dssi:fluidsynth-dssi.so:FluidSynth-DSSI",
+        "Nylon String Guitar",
+        0,
+        24,
+    ),
+    ("dssi:fluidsynth-dssi.so:FluidSynth-DSSI", "Ukulele", 1, 24),
+    (
+        "dssi:fluidsynth-dssi.so:FluidSynth-DSSI",
+        "String Ensemble 1",
+        0,
+        48,
+    ),
+    (
+        "dssi:fluidsynth-dssi.so:FluidSynth-DSSI",
+        "Orchestra Strings",
+        1,
+        48,
+    ),
+];
+
+/// Bank and program of a layer. Known plugin programs take precedence over
+/// the clip of the layer, the bank is only set for the former.
+pub fn midi_bank_program(play_parameters: &SvPlayParameters) -> (Option<u7>, u7) {
+    play_parameters
+        .plugins
+        .iter()
+        .find_map(|plugin| {
+            PLUGIN_PROGRAM_MAP
+                .iter()
+                .find(|&&(identifier, program, _, _)| {
+                    (identifier == plugin.identifier) && (program == plugin.program)
+                })
+        })
+        .map_or(
+            (None, play_parameters.midi_program()),
+            |&(_, _, bank_msb, program_number)| {
+                (Some(u7::from(bank_msb)), u7::from(program_number))
+            },
+        )
+}
@@ -0,0 +1,72 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+use clap::ArgEnum;
+
+#[derive(Debug, Copy, Clone, ArgEnum)]
+pub enum PolyphonyPriority {
+    /// Drop the quietest notes
+    #[clap(name = "keep-loudest")]
+    Loudest,
+
+    /// Drop the lowest notes
+    #[clap(name = "keep-highest")]
+    Highest,
+
+    /// Drop the oldest notes
+    #[clap(name = "keep-newest")]
+    Newest,
+}
+
+/// A note of a notes layer, with frame-based timing.
+pub struct Voice {
+    pub start: usize,
+    pub end: usize,
+    pub key: isize,
+    pub velocity: u8,
+}
+
+/// Sweeps through the voices in time order and returns the indices of the
+/// voices to be dropped to keep the number of simultaneous voices within
+/// `max_polyphony`. Voices are dropped entirely, even when they had been
+/// sounding for a while when the limit has been exceeded.
+pub fn limit_polyphony(
+    voices: &[Voice],
+    max_polyphony: usize,
+    priority: PolyphonyPriority,
+) -> HashSet<usize> {
+    let mut voice_indices = (0..voices.len()).collect::<Vec<_>>();
+    voice_indices.sort_by_key(|&index| (voices[index].start, index));
+
+    let mut active_voices: Vec<usize> = Vec::new();
+    let mut dropped_voices = HashSet::new();
+
+    for index in voice_indices {
+        active_voices.retain(|&active_index| voices[active_index].end > voices[index].start);
+        active_voices.push(index);
+
+        while active_voices.len() > max_polyphony {
+            // Ties are resolved by dropping the newer voice, except when
+            // explicitly keeping the newest ones
+            let (position, _) = active_voices
+                .iter()
+                .enumerate()
+                .min_by_key(|&(_, &active_index)| {
+                    let voice = &voices[active_index];
+
+                    match priority {
+                        PolyphonyPriority::Loudest => {
+                            (voice.velocity as isize, Reverse(voice.start))
+                        }
+                        PolyphonyPriority::Highest => (voice.key, Reverse(voice.start)),
+                        PolyphonyPriority::Newest => (voice.start as isize, Reverse(0)),
+                    }
+                })
+                .expect("no active voices");
+
+            dropped_voices.insert(active_voices.remove(position));
+        }
+    }
+
+    dropped_voices
+}
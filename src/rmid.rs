@@ -0,0 +1,52 @@
+use clap::ArgEnum;
+
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+pub enum Container {
+    /// Standard MIDI file
+    Smf,
+
+    /// Standard MIDI file wrapped in a RIFF 'RMID' chunk (.rmi)
+    Rmid,
+}
+
+/// Wraps a standard MIDI file into the 'data' chunk of a RIFF 'RMID' file.
+/// RIFF chunks are padded to even sizes, the padding is not part of the
+/// chunk size.
+pub fn wrap_rmid(smf_data: &[u8]) -> Vec<u8> {
+    let padding = smf_data.len() % 2;
+    let riff_size = 4 + 8 + smf_data.len() + padding;
+
+    let mut rmid_data = Vec::with_capacity(8 + riff_size);
+    rmid_data.extend(b"RIFF");
+    rmid_data.extend((riff_size as u32).to_le_bytes());
+    rmid_data.extend(b"RMID");
+    rmid_data.extend(b"data");
+    rmid_data.extend((smf_data.len() as u32).to_le_bytes());
+    rmid_data.extend(smf_data);
+    rmid_data.resize(rmid_data.len() + padding, 0);
+
+    rmid_data
+}
+
+/// The standard MIDI file of a RIFF 'RMID' file, `None` for other data.
+pub fn unwrap_rmid(data: &[u8]) -> Option<&[u8]> {
+    if (data.len() < 12) || !data.starts_with(b"RIFF") || (&data[8..12] != b"RMID") {
+        return None;
+    }
+
+    // Skipping other chunks (e.g. 'LIST' metadata) until the 'data' chunk
+    let mut chunks = &data[12..];
+
+    while chunks.len() >= 8 {
+        let chunk_size = u32::from_le_bytes(chunks[4..8].try_into().unwrap()) as usize;
+        let chunk_data = chunks.get(8..8 + chunk_size)?;
+
+        if chunks.starts_with(b"data") {
+            return Some(chunk_data);
+        }
+
+        chunks = chunks.get(8 + chunk_size + chunk_size % 2..)?;
+    }
+
+    None
+}
@@ -0,0 +1,78 @@
+use std::fs;
+use std::path::Path;
+
+use crate::error::Sv2MidError;
+
+/// Note value to MIDI key table of a custom scale or tuning.
+pub struct Scale {
+    entries: Vec<(f64, f64)>,
+}
+
+impl Scale {
+    /// MIDI key of a note value, fractional keys are microtonal.
+    pub fn midi_value(&self, value: f64) -> Option<f64> {
+        self.entries
+            .iter()
+            .find(|&&(scale_value, _)| scale_value == value)
+            .map(|&(_, midi_value)| midi_value)
+    }
+}
+
+/// Loads a scale file with one `value=key` pair per line. Everything after a
+/// `#` is a comment, blank lines are ignored.
+pub fn load_scale_file(path: &Path) -> Result<Scale, Sv2MidError> {
+    let mut entries = Vec::new();
+
+    for (line_index, line) in fs::read_to_string(path)
+        .map_err(|err| Sv2MidError::from(err).in_path(path))?
+        .lines()
+        .enumerate()
+    {
+        let line = line.split('#').next().unwrap_or_default().trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let (value, midi_value) = line.split_once('=').ok_or_else(|| {
+            format!(
+                "{}:{}: expected a 'value=key' pair",
+                path.display(),
+                line_index + 1
+            )
+        })?;
+
+        let parse_number = |input: &str| {
+            input
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|number| number.is_finite())
+                .ok_or_else(|| {
+                    format!(
+                        "{}:{}: invalid number '{}'",
+                        path.display(),
+                        line_index + 1,
+                        input.trim().escape_default()
+                    )
+                })
+        };
+
+        let value = parse_number(value)?;
+        let midi_value = parse_number(midi_value)?;
+
+        if !(0.0..=127.0).contains(&midi_value) {
+            return Err(format!(
+                "{}:{}: MIDI key {} out of range (expected 0-127)",
+                path.display(),
+                line_index + 1,
+                midi_value
+            )
+            .into());
+        }
+
+        entries.push((value, midi_value));
+    }
+
+    Ok(Scale { entries })
+}
@@ -1,23 +1,33 @@
-use std::error::Error;
-use std::fs::File;
-use std::io;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::Read;
 use std::path::Path;
+use std::{fmt, fs};
 
-use bzip2_rs::DecoderReader;
+use clap::ArgEnum;
 use midly::num::u7;
-use strong_xml::XmlRead;
+use strong_xml::utils::xml_unescape;
+use strong_xml::{XmlError, XmlRead, XmlResult};
 
+use crate::bzip2_ext::decompress_bzip2;
+use crate::error::Sv2MidError;
+use crate::warning::Warning;
+
+/// A Sonic Visualiser project. Only the attributes and elements used by the
+/// conversion are declared, strong_xml skips every other one, so projects of
+/// newer Sonic Visualiser versions with additional fields still load.
 #[derive(Debug, XmlRead)]
 #[xml(tag = "sv")]
 pub struct SvDocument {
     #[xml(child = "data")]
     pub data: SvData,
 
+    /// Missing from hand-written and generated projects.
     #[xml(child = "display")]
-    pub display: SvDisplay,
+    pub display: Option<SvDisplay>,
 
     #[xml(child = "selections")]
-    pub selections: SvSelections,
+    pub selections: Option<SvSelections>,
 }
 
 #[derive(Debug, XmlRead)]
@@ -89,6 +99,18 @@ pub struct SvModel {
 
     #[xml(attr = "units")]
     pub units: Option<String>,
+
+    /// Frames per column of dense models.
+    #[xml(attr = "windowSize")]
+    pub window_size: Option<usize>,
+
+    /// Frame of the first column of dense models.
+    #[xml(attr = "startFrame")]
+    pub start_frame: Option<usize>,
+
+    /// Number of bins (values per column) of dense models.
+    #[xml(attr = "yBinCount")]
+    pub y_bin_count: Option<usize>,
 }
 
 #[derive(Debug, XmlRead)]
@@ -132,8 +154,27 @@ pub struct SvDataset {
     #[xml(attr = "dimensions")]
     pub dimensions: usize,
 
+    /// Separator of the values in the rows of dense datasets.
+    #[xml(attr = "separator")]
+    pub separator: Option<String>,
+
+    /// Points of sparse datasets, and the expanded rows of dense ones.
     #[xml(child = "point")]
     pub points: Vec<SvPoint>,
+
+    #[xml(child = "row")]
+    pub rows: Vec<SvDenseRow>,
+}
+
+/// A column of a dense three-dimensional model, one value per bin.
+#[derive(Debug, XmlRead)]
+#[xml(tag = "row")]
+pub struct SvDenseRow {
+    #[xml(attr = "n")]
+    pub n: usize,
+
+    #[xml(text)]
+    pub values: String,
 }
 
 #[derive(Debug, XmlRead)]
@@ -142,8 +183,9 @@ pub struct SvPoint {
     #[xml(attr = "frame")]
     pub frame: usize,
 
+    /// Pitch of notes, fractional for microtonal notes.
     #[xml(attr = "value")]
-    pub value: Option<usize>,
+    pub value: Option<f64>,
 
     #[xml(attr = "duration")]
     pub duration: Option<usize>,
@@ -151,11 +193,15 @@ pub struct SvPoint {
     #[xml(attr = "level")]
     pub level: Option<f64>,
 
+    /// Missing on unlabelled points of some layer types, see `label()`.
     #[xml(attr = "label")]
-    pub label: String,
+    pub label: Option<String>,
 
     #[xml(attr = "height")]
     pub height: Option<f64>,
+
+    #[xml(attr = "pan")]
+    pub pan: Option<f64>,
 }
 
 #[derive(Debug, XmlRead)]
@@ -175,6 +221,14 @@ pub struct SvLayer {
 
     #[xml(attr = "presentationName")]
     pub presentation_name: Option<String>,
+
+    /// Name of the colour in the colour database of Sonic Visualiser.
+    #[xml(attr = "colourName")]
+    pub colour_name: Option<String>,
+
+    /// `#RRGGBB` value of the colour, written by newer versions.
+    #[xml(attr = "colour")]
+    pub colour: Option<String>,
     // TODO: Other properties
 }
 
@@ -191,21 +245,352 @@ pub struct SvSelections {
 }
 
 impl SvDocument {
-    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
-        let mut bzip2_decoder = DecoderReader::new(File::open(path)?);
-        let xml_data = io::read_to_string(&mut bzip2_decoder)?;
+    pub fn load(path: &Path) -> Result<Self, Sv2MidError> {
+        let name = path.display().to_string();
+        let data = fs::read(path).map_err(|err| Sv2MidError::from(err).in_source(&name))?;
+
+        SvDocument::from_bytes(&data, Some(&name))
+    }
+
+    /// Reads a compressed or plain project, errors are prefixed with `name`.
+    pub fn from_reader<R: Read>(mut reader: R, name: Option<&str>) -> Result<Self, Sv2MidError> {
+        let mut data = Vec::new();
+
+        match reader.read_to_end(&mut data) {
+            Ok(_) => SvDocument::from_bytes(&data, name),
+            Err(err) => Err(Sv2MidError::from(err).in_optional_source(name)),
+        }
+    }
+
+    /// Parses a compressed or plain project, errors are prefixed with `name`.
+    pub fn from_bytes(data: &[u8], name: Option<&str>) -> Result<Self, Sv2MidError> {
+        SvDocument::parse(data).map_err(|err| err.in_optional_source(name))
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, Sv2MidError> {
+        // Sonic Visualiser always compresses its projects, hand-written and
+        // generated ones are often plain XML
+        let xml_data = if data.starts_with(b"BZh") {
+            decompress_bzip2(data).map_err(Sv2MidError::Decompression)?
+        } else {
+            data.to_vec()
+        };
+        let xml_data = String::from_utf8(xml_data).map_err(Sv2MidError::Encoding)?;
+
+        let mut sv_document = SvDocument::from_str(&xml_data)?;
+        sv_document.unescape_attributes()?;
+        sv_document.expand_dense_datasets()?;
+
+        Ok(sv_document)
+    }
+
+    /// Turns the cells of dense models (colour 3D plots) into notes: one
+    /// point per column and bin, the bin number being the value and the
+    /// magnitude between the minimum and the maximum of the model the level.
+    /// Cells at or below the minimum are silent and skipped.
+    ///
+    /// Dense models are not playable in Sonic Visualiser, they get neutral
+    /// play parameters.
+    fn expand_dense_datasets(&mut self) -> Result<(), Sv2MidError> {
+        for model in self.data.models.iter().filter(|model| model.is_dense()) {
+            let dataset = match self
+                .data
+                .datasets
+                .iter_mut()
+                .find(|dataset| Some(dataset.id) == model.dataset)
+            {
+                Some(dataset) => dataset,
+                None => continue,
+            };
+
+            let window_size = model.window_size.unwrap_or(1);
+            let minimum = model.minimum.unwrap_or(0.0);
+            let maximum = model.maximum.unwrap_or(1.0);
+
+            for row in dataset.rows.iter() {
+                let values = match dataset.separator.as_deref() {
+                    Some(separator) if !separator.trim().is_empty() => {
+                        row.values.split(separator).collect::<Vec<_>>()
+                    }
+                    _ => row.values.split_whitespace().collect::<Vec<_>>(),
+                };
+
+                for (bin, value) in values.into_iter().enumerate() {
+                    let value = value.trim().parse::<f64>().map_err(|_| {
+                        XmlError::FromStr(
+                            format!(
+                                "invalid value '{}' in row {} of dataset {}",
+                                value.escape_default(),
+                                row.n,
+                                dataset.id
+                            )
+                            .into(),
+                        )
+                    })?;
+
+                    if value <= minimum {
+                        continue;
+                    }
+
+                    dataset.points.push(SvPoint {
+                        frame: model.start_frame.unwrap_or(0) + row.n * window_size,
+                        value: Some(bin as f64),
+                        duration: Some(window_size),
+                        level: Some(((value - minimum) / (maximum - minimum)).min(1.0)),
+                        label: None,
+                        height: None,
+                        pan: None,
+                    });
+                }
+            }
+
+            dataset.points.sort_by_key(|point| point.frame);
+
+            if self.get_play_parameters_by_id(model.id).is_none() {
+                self.data.play_parameters.push(SvPlayParameters {
+                    mute: false,
+                    pan: 0.0,
+                    gain: 1.0,
+                    clip_id: String::new(),
+                    model: model.id,
+                    plugins: Vec::new(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// strong_xml leaves the entities of attribute values undecoded, names and
+    /// labels would contain literal `&amp;` and `&#233;` sequences otherwise.
+    fn unescape_attributes(&mut self) -> XmlResult<()> {
+        fn unescape(value: &mut String) -> XmlResult<()> {
+            let unescaped = match xml_unescape(value)? {
+                Cow::Owned(unescaped) => Some(unescaped),
+                Cow::Borrowed(_) => None,
+            };
+
+            if let Some(unescaped) = unescaped {
+                *value = unescaped;
+            }
+
+            Ok(())
+        }
+
+        for model in self.data.models.iter_mut() {
+            unescape(&mut model.name)?;
+        }
+
+        for layer in self.data.layers.iter_mut() {
+            unescape(&mut layer.name)?;
+
+            if let Some(presentation_name) = layer.presentation_name.as_mut() {
+                unescape(presentation_name)?;
+            }
+        }
+
+        for point in self
+            .data
+            .datasets
+            .iter_mut()
+            .flat_map(|dataset| dataset.points.iter_mut())
+        {
+            if let Some(label) = point.label.as_mut() {
+                unescape(label)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Converts the frames of sparse models stored in multiples of the
+    /// model resolution into samples. Dense models are left alone, their
+    /// columns are positioned by the window size.
+    pub fn apply_frame_unit(&mut self, frame_unit: FrameUnit) {
+        if frame_unit == FrameUnit::Samples {
+            return;
+        }
+
+        for model in self.data.models.iter().filter(|model| !model.is_dense()) {
+            let resolution = model.resolution.unwrap_or(1);
 
-        Ok(SvDocument::from_str(&xml_data)?)
+            for point in self
+                .data
+                .datasets
+                .iter_mut()
+                .filter(|dataset| Some(dataset.id) == model.dataset)
+                .flat_map(|dataset| dataset.points.iter_mut())
+            {
+                point.frame *= resolution;
+                point.duration = point.duration.map(|duration| duration * resolution);
+            }
+        }
+    }
+
+    /// Sparse models whose frames look like multiples of the resolution
+    /// instead of samples: Sonic Visualiser quantizes frames to the
+    /// resolution, these ones are not quantized and end within the first
+    /// 1/resolution of the main audio.
+    pub fn get_models_with_resolution_frames(&self) -> Vec<&SvModel> {
+        let main_model_end = match self.get_main_model() {
+            Some(main_model) => main_model.end,
+            None => return Vec::new(),
+        };
+
+        self.data
+            .models
+            .iter()
+            .filter(|model| !model.is_dense())
+            .filter(|model| {
+                let resolution = model.resolution.unwrap_or(1);
+
+                let dataset = match model
+                    .dataset
+                    .and_then(|dataset_id| self.get_dataset_by_id(dataset_id))
+                {
+                    Some(dataset) => dataset,
+                    None => return false,
+                };
+
+                let quantized = dataset
+                    .points
+                    .iter()
+                    .all(|point| point.frame % resolution == 0);
+
+                (resolution > 1)
+                    && !quantized
+                    && dataset.time_range().is_some_and(|(_, end_frame)| {
+                        end_frame.saturating_mul(resolution) <= main_model_end
+                    })
+            })
+            .collect()
     }
 
     pub fn get_model_by_id(&self, id: usize) -> Option<&SvModel> {
         self.data.models.iter().find(|model| model.id == id)
     }
 
+    /// The model of the main audio file, defining the sample rate of the session.
+    pub fn get_main_model(&self) -> Option<&SvModel> {
+        self.data
+            .models
+            .iter()
+            .find(|model| model.main_model == Some(true))
+    }
+
+    /// Model ids occurring more than once, `get_model_by_id` returns the first
+    /// model of these.
+    pub fn duplicate_model_ids(&self) -> Vec<usize> {
+        let mut model_ids = self
+            .data
+            .models
+            .iter()
+            .map(|model| model.id)
+            .collect::<Vec<_>>();
+
+        model_ids.sort_unstable();
+
+        let mut duplicate_model_ids = model_ids
+            .windows(2)
+            .filter(|window| window[0] == window[1])
+            .map(|window| window[0])
+            .collect::<Vec<_>>();
+
+        duplicate_model_ids.dedup();
+        duplicate_model_ids
+    }
+
     pub fn get_dataset_by_id(&self, id: usize) -> Option<&SvDataset> {
         self.data.datasets.iter().find(|dataset| dataset.id == id)
     }
 
+    /// Looks up the model and the dataset of a layer, broken references of
+    /// hand-edited projects are reported with the ids involved.
+    pub fn get_layer_model_and_dataset(
+        &self,
+        layer: &SvLayer,
+    ) -> Result<(&SvModel, &SvDataset), Sv2MidError> {
+        let model = self
+            .get_model_by_id(layer.model)
+            .ok_or_else(|| Sv2MidError::MissingModel {
+                layer_id: layer.id,
+                layer_name: layer.name.clone(),
+                model_id: layer.model,
+            })?;
+
+        let dataset = model
+            .dataset
+            .and_then(|dataset_id| self.get_dataset_by_id(dataset_id))
+            .ok_or_else(|| Sv2MidError::MissingDataset {
+                model_id: model.id,
+                model_name: model.name.clone(),
+                dataset_id: model.dataset,
+            })?;
+
+        Ok((model, dataset))
+    }
+
+    /// Checks the model and the dataset of the layer against the
+    /// requirements of its layer type in `LAYER_TYPE_SUPPORT`. Layers without
+    /// a model or a dataset pass, those are reported by
+    /// `get_layer_model_and_dataset`.
+    fn layer_mismatch(&self, layer: &SvLayer) -> Option<LayerMismatch> {
+        let support = LAYER_TYPE_SUPPORT
+            .iter()
+            .find(|support| support.layer_type == layer.r#type)?;
+        let model = self.get_model_by_id(layer.model)?;
+
+        if !model.is_derived()
+            && ((model.r#type != support.model_type)
+                || (model.subtype.as_deref() != support.model_subtype))
+        {
+            return Some(LayerMismatch::ModelType {
+                model_id: model.id,
+                model_name: model.name.clone(),
+                model_type: model_type_name(&model.r#type, model.subtype.as_deref()),
+                layer_type: layer.r#type.clone(),
+                expected: model_type_name(support.model_type, support.model_subtype),
+            });
+        }
+
+        let dataset = model
+            .dataset
+            .and_then(|dataset_id| self.get_dataset_by_id(dataset_id))?;
+
+        let incomplete_point_count = match support.complete_points {
+            true => dataset
+                .points
+                .iter()
+                .filter(|point| point.value.is_none() || point.duration.is_none())
+                .count(),
+            false => 0,
+        };
+
+        if support.dimensions.contains(&dataset.dimensions) && (incomplete_point_count == 0) {
+            return None;
+        }
+
+        let mut expected = dimension_count_name(support.dimensions);
+        if support.complete_points {
+            expected += ", every point with a value and a duration";
+        }
+
+        let mut actual = dimension_count_name(&[dataset.dimensions]);
+        if incomplete_point_count > 0 {
+            actual += &format!(
+                " and {} points without a value or a duration",
+                incomplete_point_count
+            );
+        }
+
+        Some(LayerMismatch::DatasetShape {
+            dataset_id: dataset.id,
+            layer_type: layer.r#type.clone(),
+            expected,
+            actual,
+        })
+    }
+
     pub fn get_play_parameters_by_id(&self, id: usize) -> Option<&SvPlayParameters> {
         self.data
             .play_parameters
@@ -222,9 +607,271 @@ impl SvDocument {
             .iter()
             .filter(move |layer| layer.r#type == layer_type)
     }
+
+    /// Layers displaying the model, usually one but layers may share models.
+    pub fn get_layers_by_model_id(&self, model_id: usize) -> impl Iterator<Item = &SvLayer> {
+        self.data
+            .layers
+            .iter()
+            .filter(move |layer| layer.model == model_id)
+    }
+
+    pub fn get_datasets_unreferenced_by_any_model(&self) -> Vec<&SvDataset> {
+        self.data
+            .datasets
+            .iter()
+            .filter(|dataset| {
+                !self
+                    .data
+                    .models
+                    .iter()
+                    .any(|model| model.dataset == Some(dataset.id))
+            })
+            .collect()
+    }
+
+    pub fn get_models_unreferenced_by_any_layer(&self) -> Vec<&SvModel> {
+        self.data
+            .models
+            .iter()
+            .filter(|model| self.get_layers_by_model_id(model.id).next().is_none())
+            .collect()
+    }
+
+    /// Structural problems of the project, checked before the conversion.
+    /// Orphaned data is left out of the export, layers with mismatching
+    /// datasets are either rejected or skipped by the caller.
+    pub fn validate(&self) -> SvValidation {
+        let layer_mismatches = self
+            .data
+            .layers
+            .iter()
+            .filter_map(|layer| {
+                self.layer_mismatch(layer)
+                    .map(|mismatch| (layer.id, mismatch))
+            })
+            .collect();
+
+        let duplicate_model_ids = Some(self.duplicate_model_ids())
+            .filter(|model_ids| !model_ids.is_empty())
+            .map(|model_ids| Warning::DuplicateModelIds { model_ids });
+
+        let orphan_datasets = self
+            .get_datasets_unreferenced_by_any_model()
+            .into_iter()
+            .map(|dataset| Warning::OrphanedDataset {
+                dataset_id: dataset.id,
+            });
+
+        let orphan_models = self
+            .get_models_unreferenced_by_any_layer()
+            .into_iter()
+            .map(|model| Warning::OrphanedModel {
+                model_id: model.id,
+                model_name: model.name.clone(),
+            });
+
+        let warnings = duplicate_model_ids
+            .into_iter()
+            .chain(orphan_datasets)
+            .chain(orphan_models)
+            .collect();
+
+        SvValidation {
+            warnings,
+            layer_mismatches,
+        }
+    }
+}
+
+/// Result of `SvDocument::validate`.
+pub struct SvValidation {
+    pub warnings: Vec<Warning>,
+
+    /// Layers by id, whose models or datasets don't match their layer type.
+    pub layer_mismatches: BTreeMap<usize, LayerMismatch>,
+}
+
+/// Model and dataset requirements of an exported layer type.
+struct LayerTypeSupport {
+    layer_type: &'static str,
+    model_type: &'static str,
+
+    /// `None` for models without a subtype.
+    model_subtype: Option<&'static str>,
+
+    /// Accepted dimension counts of the dataset.
+    dimensions: &'static [usize],
+
+    /// Every point has to have a value and a duration.
+    complete_points: bool,
+}
+
+/// The layer types the conversion exports and the models they display.
+/// Derived models (alignment paths) are exempt from the model type check,
+/// their layers are only exported on request.
+const LAYER_TYPE_SUPPORT: &[LayerTypeSupport] = &[
+    LayerTypeSupport {
+        layer_type: "notes",
+        model_type: "sparse",
+        model_subtype: Some("note"),
+        dimensions: &[3, 4],
+        complete_points: true,
+    },
+    LayerTypeSupport {
+        layer_type: "colour3dplot",
+        model_type: "dense",
+        model_subtype: None,
+        dimensions: &[3],
+        complete_points: false,
+    },
+    LayerTypeSupport {
+        layer_type: "timeinstants",
+        model_type: "sparse",
+        model_subtype: None,
+        dimensions: &[1],
+        complete_points: false,
+    },
+    LayerTypeSupport {
+        layer_type: "text",
+        model_type: "sparse",
+        model_subtype: Some("text"),
+        dimensions: &[2],
+        complete_points: false,
+    },
+];
+
+/// "sparse/note" style name of a model type and subtype.
+fn model_type_name(model_type: &str, model_subtype: Option<&str>) -> String {
+    match model_subtype {
+        Some(model_subtype) => format!("{}/{}", model_type, model_subtype),
+        None => model_type.to_owned(),
+    }
+}
+
+/// "3 or 4 dimensions" style name of dimension counts.
+fn dimension_count_name(dimensions: &[usize]) -> String {
+    let counts = dimensions
+        .iter()
+        .map(|dimensions| dimensions.to_string())
+        .collect::<Vec<_>>()
+        .join(" or ");
+
+    match dimensions {
+        [1] => format!("{} dimension", counts),
+        _ => format!("{} dimensions", counts),
+    }
+}
+
+/// A layer whose model or dataset doesn't match its layer type.
+#[derive(Debug, Clone)]
+pub enum LayerMismatch {
+    ModelType {
+        model_id: usize,
+        model_name: String,
+        model_type: String,
+        layer_type: String,
+        expected: String,
+    },
+
+    DatasetShape {
+        dataset_id: usize,
+        layer_type: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for LayerMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayerMismatch::ModelType {
+                model_id,
+                model_name,
+                model_type,
+                layer_type,
+                expected,
+            } => write!(
+                f,
+                "model {} ('{}') is a {} model, {} layers expect a {} model",
+                model_id,
+                model_name.escape_default(),
+                model_type.escape_default(),
+                layer_type,
+                expected
+            ),
+            LayerMismatch::DatasetShape {
+                dataset_id,
+                layer_type,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "dataset {} has {}, {} layers expect {}",
+                dataset_id, actual, layer_type, expected
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ArgEnum)]
+pub enum GainCurve {
+    /// Volume proportional to the gain
+    Linear,
+
+    /// Volume following the General MIDI volume curve (40·log10), matching
+    /// the perceived loudness of the gain slider
+    Log,
+}
+
+impl SvModel {
+    /// Models generated by Sonic Visualiser for aligning recordings, these
+    /// are not part of the annotated music.
+    pub fn is_derived(&self) -> bool {
+        (self.r#type == "alignment") || (self.subtype.as_deref() == Some("path"))
+    }
+
+    /// Models of colour 3D plot layers, storing a column of values per frame.
+    pub fn is_dense(&self) -> bool {
+        self.r#type == "dense"
+    }
+
+    /// Fractional MIDI key of a note value according to the units of the
+    /// model. Frequencies are converted (A4 = 440 Hz), MIDI pitches and
+    /// values of unknown units are passed through, out-of-range keys are left
+    /// to the caller to report.
+    pub fn value_to_midi_key(&self, value: f64) -> f64 {
+        match self.units.as_deref() {
+            Some("Hz") => 69.0 + 12.0 * (value / 440.0).log2(),
+            _ => value,
+        }
+    }
+
+    /// Range of the note values, the bin numbers of dense models.
+    pub fn value_range(&self) -> Option<(f64, f64)> {
+        if self.is_dense() {
+            self.y_bin_count
+                .map(|bin_count| (0.0, bin_count.saturating_sub(1) as f64))
+        } else {
+            self.minimum.zip(self.maximum)
+        }
+    }
 }
 
 impl SvPlayParameters {
+    /// Maps the gain (default 1.0) to the 0-127 MIDI volume range (default
+    /// 100). The volume saturates before the top of the 0.0-4.0 gain range,
+    /// from a gain of 1.27 on with the linear curve and about 1.61 on with
+    /// the log curve.
+    pub fn midi_volume(&self, gain_curve: GainCurve) -> u7 {
+        let volume = match gain_curve {
+            GainCurve::Linear => self.gain * 100.0,
+            GainCurve::Log => self.gain.sqrt() * 100.0,
+        };
+
+        u7::from(volume.clamp(0.0, 127.0).round() as u8)
+    }
+
     pub fn midi_program(&self) -> u7 {
         u7::from(match self.clip_id.as_str() {
             "piano" => 0,
@@ -254,12 +901,286 @@ impl SvPlayParameters {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+pub enum FrameUnit {
+    /// Frames in samples, as saved by Sonic Visualiser
+    Samples,
+
+    /// Frames of sparse models in multiples of the model resolution, as
+    /// saved by some plugin exporters
+    Resolution,
+}
+
+#[derive(Debug, Copy, Clone, ArgEnum)]
+pub enum NameSource {
+    /// The `name` attribute of the layer
+    Name,
+
+    /// The `presentationName` attribute of the layer
+    #[clap(name = "presentation")]
+    PresentationName,
+
+    /// The `presentationName` attribute when present, `name` otherwise
+    Auto,
+}
+
+impl SvDataset {
+    pub fn note_count(&self) -> usize {
+        self.points.len()
+    }
+
+    /// First frame and last end frame (accounting for durations) of the
+    /// dataset, `None` for empty datasets.
+    pub fn time_range(&self) -> Option<(usize, usize)> {
+        let min_frame = self.points.iter().map(|point| point.frame).min()?;
+        let max_end_frame = self
+            .points
+            .iter()
+            .map(|point| point.frame + point.duration.unwrap_or(0))
+            .max()?;
+
+        Some((min_frame, max_end_frame))
+    }
+
+    /// Points starting within the `start..end` frame range. Makes no
+    /// assumptions about the point order, scans the whole dataset.
+    pub fn get_points_in_range(&self, start: usize, end: usize) -> impl Iterator<Item = &SvPoint> {
+        self.points
+            .iter()
+            .filter(move |point| (start..end).contains(&point.frame))
+    }
+
+    /// Points starting within the `start..end` frame range. Assumes the
+    /// points are sorted by frame, as Sonic Visualiser saves them.
+    pub fn get_points_in_sorted_range(&self, start: usize, end: usize) -> &[SvPoint] {
+        let range_start = self.points.partition_point(|point| point.frame < start);
+        let range_end = self.points.partition_point(|point| point.frame < end);
+
+        &self.points[range_start..range_end.max(range_start)]
+    }
+}
+
+impl SvPoint {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or("")
+    }
+}
+
 impl SvLayer {
-    pub fn midi_name(&self) -> &str {
-        if let Some(presentation_name) = &self.presentation_name {
-            presentation_name
-        } else {
-            &self.name
+    pub fn midi_name(&self, name_source: NameSource) -> &str {
+        let presentation_name = self.presentation_name.as_deref().unwrap_or_default();
+
+        match name_source {
+            NameSource::Name if !self.name.is_empty() => &self.name,
+            NameSource::Name => presentation_name,
+            NameSource::PresentationName if !presentation_name.is_empty() => presentation_name,
+            NameSource::PresentationName => &self.name,
+            NameSource::Auto => self.presentation_name.as_deref().unwrap_or(&self.name),
+        }
+    }
+
+    /// The attribute `midi_name` falls back to when the attribute chosen by
+    /// `name_source` is empty, `None` when no fallback happens.
+    pub fn midi_name_fallback(&self, name_source: NameSource) -> Option<&'static str> {
+        let presentation_name = self.presentation_name.as_deref().unwrap_or_default();
+
+        match name_source {
+            NameSource::Name if self.name.is_empty() => Some("presentationName"),
+            NameSource::PresentationName if presentation_name.is_empty() => Some("name"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(xml: &str) -> SvDocument {
+        SvDocument::from_bytes(xml.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn midi_name_falls_back_to_the_other_attribute() {
+        let sv_document = document(
+            r#"<sv><data>
+                <layer id="1" type="notes" name="" presentationName="Melody" model="2"/>
+                <layer id="3" type="notes" name="Bass" model="4"/>
+            </data></sv>"#,
+        );
+        let layers = &sv_document.data.layers;
+
+        assert_eq!(layers[0].midi_name(NameSource::Name), "Melody");
+        assert_eq!(
+            layers[0].midi_name_fallback(NameSource::Name),
+            Some("presentationName")
+        );
+        assert_eq!(
+            layers[0].midi_name_fallback(NameSource::PresentationName),
+            None
+        );
+
+        assert_eq!(layers[1].midi_name(NameSource::PresentationName), "Bass");
+        assert_eq!(
+            layers[1].midi_name_fallback(NameSource::PresentationName),
+            Some("name")
+        );
+        assert_eq!(layers[1].midi_name_fallback(NameSource::Auto), None);
+    }
+
+    #[test]
+    fn attribute_entities_decoded() {
+        let sv_document = document(
+            r#"<sv><data>
+                <dataset id="1" dimensions="3">
+                    <point frame="0" value="60" duration="100" label="C &amp; D&#233;"/>
+                </dataset>
+                <layer id="2" type="notes" name="Rock &amp; Roll" presentationName="Caf&#233;" model="3"/>
+            </data></sv>"#,
+        );
+
+        let layer = &sv_document.data.layers[0];
+        assert_eq!(layer.name, "Rock & Roll");
+        assert_eq!(layer.presentation_name.as_deref(), Some("Café"));
+        assert_eq!(sv_document.data.datasets[0].points[0].label(), "C & Dé");
+    }
+
+    #[test]
+    fn resolution_frame_unit_multiplies_frames() {
+        let xml = r#"<sv><data>
+                <model id="1" name="Notes" sampleRate="44100" start="0" end="44100" type="sparse" dimensions="3" resolution="512" dataset="2" subtype="note"/>
+                <dataset id="2" dimensions="3"><point frame="10" value="60" duration="4" label=""/></dataset>
+                <model id="3" name="Hits" sampleRate="44100" start="0" end="44100" type="sparse" dimensions="1" resolution="256" dataset="4"/>
+                <dataset id="4" dimensions="1"><point frame="3" label=""/></dataset>
+            </data></sv>"#;
+        let frames = |sv_document: &SvDocument| {
+            sv_document
+                .data
+                .datasets
+                .iter()
+                .flat_map(|dataset| dataset.points.iter())
+                .map(|point| (point.frame, point.duration))
+                .collect::<Vec<_>>()
+        };
+
+        let mut sv_document = document(xml);
+        sv_document.apply_frame_unit(FrameUnit::Samples);
+        assert_eq!(frames(&sv_document), [(10, Some(4)), (3, None)]);
+
+        let mut sv_document = document(xml);
+        sv_document.apply_frame_unit(FrameUnit::Resolution);
+        assert_eq!(frames(&sv_document), [(5120, Some(2048)), (768, None)]);
+    }
+
+    #[test]
+    fn unknown_attributes_and_elements_ignored() {
+        let sv_document = document(
+            r#"<sv futureVersion="9"><data>
+                <model id="1" name="Notes" sampleRate="44100" start="0" end="44100" type="sparse" dimensions="3" resolution="1" dataset="2" subtype="note" sparkle="yes"/>
+                <dataset id="2" dimensions="3" compression="none">
+                    <point frame="10" value="60" duration="4" label="la" confidence="0.9"/>
+                    <annotation author="someone">not a point</annotation>
+                </dataset>
+                <timeline id="7"><marker frame="0"/></timeline>
+                <layer id="3" type="notes" name="Melody" model="1" glow="2"/>
+            </data><future/></sv>"#,
+        );
+
+        assert_eq!(sv_document.data.models.len(), 1);
+        assert_eq!(sv_document.data.layers[0].name, "Melody");
+
+        let points = &sv_document.data.datasets[0].points;
+        assert_eq!(points.len(), 1);
+        assert_eq!((points[0].frame, points[0].duration), (10, Some(4)));
+        assert_eq!(points[0].label(), "la");
+    }
+
+    #[test]
+    fn points_in_half_open_frame_range() {
+        let sv_document = document(
+            r#"<sv><data>
+                <dataset id="1" dimensions="1">
+                    <point frame="0" label=""/>
+                    <point frame="10" label=""/>
+                    <point frame="10" label=""/>
+                    <point frame="20" label=""/>
+                    <point frame="30" label=""/>
+                </dataset>
+            </data></sv>"#,
+        );
+        let dataset = &sv_document.data.datasets[0];
+        let frames =
+            |points: Vec<&SvPoint>| points.iter().map(|point| point.frame).collect::<Vec<_>>();
+
+        for (start, end, expected_frames) in [
+            (10, 30, &[10, 10, 20][..]),
+            (0, 10, &[0]),
+            (11, 20, &[]),
+            (30, 31, &[30]),
+            (20, 10, &[]),
+            (0, usize::MAX, &[0, 10, 10, 20, 30]),
+        ] {
+            assert_eq!(
+                frames(dataset.get_points_in_range(start, end).collect()),
+                expected_frames
+            );
+            assert_eq!(
+                frames(
+                    dataset
+                        .get_points_in_sorted_range(start, end)
+                        .iter()
+                        .collect()
+                ),
+                expected_frames
+            );
         }
     }
+
+    #[test]
+    fn midi_volume_saturates_above_full_volume() {
+        let midi_volume = |gain: f64, gain_curve: GainCurve| {
+            SvPlayParameters {
+                mute: false,
+                pan: 0.0,
+                gain,
+                clip_id: String::from("piano"),
+                model: 1,
+                plugins: Vec::new(),
+            }
+            .midi_volume(gain_curve)
+            .as_int()
+        };
+
+        for gain_curve in [GainCurve::Linear, GainCurve::Log] {
+            assert_eq!(midi_volume(0.0, gain_curve), 0);
+            assert_eq!(midi_volume(1.0, gain_curve), 100);
+            assert_eq!(midi_volume(4.0, gain_curve), 127);
+        }
+
+        assert_eq!(midi_volume(0.5, GainCurve::Linear), 50);
+        assert_eq!(midi_volume(1.26, GainCurve::Linear), 126);
+        assert_eq!(midi_volume(1.27, GainCurve::Linear), 127);
+        assert_eq!(midi_volume(0.25, GainCurve::Log), 50);
+        assert_eq!(midi_volume(1.6, GainCurve::Log), 126);
+        assert_eq!(midi_volume(1.62, GainCurve::Log), 127);
+    }
+
+    #[test]
+    fn duplicate_model_ids_reported() {
+        let sv_document = document(
+            r#"<sv><data>
+                <model id="1" name="First" sampleRate="44100" start="0" end="44100" type="sparse" dimensions="1" resolution="1" dataset="2"/>
+                <model id="1" name="Second" sampleRate="44100" start="0" end="44100" type="sparse" dimensions="1" resolution="1" dataset="2"/>
+                <dataset id="2" dimensions="1"><point frame="0" label=""/></dataset>
+                <layer id="3" type="timeinstants" name="Hits" model="1"/>
+            </data></sv>"#,
+        );
+
+        let validation = sv_document.validate();
+        assert!(validation.warnings.iter().any(|warning| matches!(
+            warning,
+            Warning::DuplicateModelIds { model_ids } if model_ids == &[1]
+        )));
+        assert_eq!(sv_document.get_model_by_id(1).unwrap().name, "First");
+    }
 }
@@ -0,0 +1,116 @@
+use std::error::Error;
+
+use crate::utils::Seconds;
+
+pub struct TempoChange {
+    pub beat: f64,
+    pub bpm: f64,
+
+    /// Position of the change in the project.
+    pub seconds: Seconds,
+}
+
+/// Piecewise constant tempo of the exported file, always starting at beat 0.
+pub struct TempoMap {
+    changes: Vec<TempoChange>,
+}
+
+impl TempoMap {
+    /// Tempo map of the given `(beat, bpm)` changes, which must be in
+    /// ascending order of beats. The initial tempo applies until the first
+    /// change, unless that one is at beat 0.
+    pub fn new(initial_bpm: f64, beat_bpms: &[(f64, f64)]) -> TempoMap {
+        let mut changes = vec![TempoChange {
+            beat: 0.0,
+            bpm: initial_bpm,
+            seconds: Seconds(0.0),
+        }];
+
+        for &(beat, bpm) in beat_bpms {
+            let previous = changes.last().unwrap();
+
+            if beat == 0.0 {
+                changes[0].bpm = bpm;
+                continue;
+            }
+
+            let seconds =
+                Seconds(previous.seconds.0 + (beat - previous.beat) * 60.0 / previous.bpm);
+            changes.push(TempoChange { beat, bpm, seconds });
+        }
+
+        TempoMap { changes }
+    }
+
+    pub fn changes(&self) -> &[TempoChange] {
+        &self.changes
+    }
+
+    pub fn initial_bpm(&self) -> f64 {
+        self.changes[0].bpm
+    }
+
+    /// Number of beats elapsed until the given position.
+    pub fn beats(&self, seconds: Seconds) -> f64 {
+        let change = self
+            .changes
+            .iter()
+            .rev()
+            .find(|change| change.seconds.0 <= seconds.0)
+            .unwrap_or(&self.changes[0]);
+
+        change.beat + (seconds.0 - change.seconds.0) * change.bpm / 60.0
+    }
+
+    /// Position of the given beat, the inverse of `beats`.
+    pub fn seconds(&self, beats: f64) -> Seconds {
+        let change = self
+            .changes
+            .iter()
+            .rev()
+            .find(|change| change.beat <= beats)
+            .unwrap_or(&self.changes[0]);
+
+        Seconds(change.seconds.0 + (beats - change.beat) * 60.0 / change.bpm)
+    }
+}
+
+/// Checks that the `(beat, bpm)` changes are in strictly ascending order of
+/// beats.
+pub fn validate_tempo_changes(beat_bpms: &[(f64, f64)]) -> Result<(), String> {
+    for window in beat_bpms.windows(2) {
+        let ((beat_a, bpm_a), (beat_b, bpm_b)) = (window[0], window[1]);
+
+        if beat_a == beat_b {
+            return Err(format!(
+                "conflicting tempo changes at beat {}: {} and {} bpm",
+                beat_a, bpm_a, bpm_b
+            ));
+        } else if beat_a > beat_b {
+            return Err(format!(
+                "tempo changes out of order: beat {} follows beat {}",
+                beat_b, beat_a
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a `BEAT:BPM` tempo change.
+pub fn parse_tempo_change(input: &str) -> Result<(f64, f64), Box<dyn Error + Send + Sync>> {
+    let (beat, bpm) = input
+        .split_once(':')
+        .ok_or("expected BEAT:BPM, e.g. 16:140")?;
+
+    let beat = beat.trim().parse::<f64>()?;
+    let bpm = bpm.trim().parse::<f64>()?;
+
+    if !beat.is_finite() || (beat < 0.0) {
+        Err("the beat must not be negative".into())
+    } else if !bpm.is_finite() || (bpm <= 0.0) {
+        Err("the tempo must be positive".into())
+    } else {
+        Ok((beat, bpm))
+    }
+}
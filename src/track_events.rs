@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+
+use midly::num::{u28, u7};
+use midly::{MidiMessage, Track, TrackEvent, TrackEventKind};
+
+use crate::error::ConversionError;
+use crate::midly_ext::TrackEventKindExt;
+use crate::utils::Seconds;
+
+/// A converted event positioned in absolute MIDI ticks, before the events
+/// get sorted and delta-encoded into their tracks.
+pub struct AbsoluteTrackEvent<'a> {
+    /// Index of the track the event belongs to.
+    pub track: usize,
+
+    /// Name of the layer (or model) the event originates from, used
+    /// for error reporting.
+    pub source: &'a str,
+
+    /// Absolute MIDI position of the event.
+    pub ticks: usize,
+
+    /// Absolute MIDI position when the note/event has actually been
+    /// started (the corresponding NoteOn event for NoteOff events).
+    /// Only used as an additional sorting key when preparing events for
+    /// delta-encoding and linting (overlaps, excessive polyphony).
+    ///
+    /// This field has been introduced for properly resolving that case
+    /// when a note stops at the same moment when a new one starts.
+    /// Event sorting must ensure that the NoteOn event of Note#2 must
+    /// not preceed the NoteOff event of Note#1 for obvious reasons.
+    /// ```text
+    /// Time   |-1- - - - -2- - - - -3-|
+    ///        |           V           |
+    /// Note#1 | [=========]           |
+    /// Note#2 |           [=========] |
+    /// ```
+    pub ticks_event_start: usize,
+
+    /// The position of the event in seconds, used for error reporting.
+    /// This field has been introduced because the "Sonic Visualiser
+    /// seconds"->"MIDI ticks" conversion is lossy and caused extreme
+    /// precision loss at the error message timestamps in some cases.
+    pub seconds: Seconds,
+
+    /// MIDI event data.
+    pub kind: TrackEventKind<'a>,
+}
+
+pub fn describe_event(event: &AbsoluteTrackEvent) -> String {
+    format!(
+        "{:?} at tick {} ({}) of layer '{}'",
+        event.kind,
+        event.ticks,
+        event.seconds,
+        event.source.escape_default()
+    )
+}
+
+/// Drops the controller events repeating the previous value of the same
+/// controller on the same port and channel, the events already in the tracks
+/// (the channel setup) count as previous values. Returns the number of dropped
+/// events.
+pub fn dedup_controllers(
+    events: &mut Vec<AbsoluteTrackEvent>,
+    midi_tracks: &[Track],
+    track_ports: &[u8],
+) -> usize {
+    let mut controller_values = HashMap::new();
+
+    for (track, midi_track) in midi_tracks.iter().enumerate() {
+        for track_event in midi_track.iter() {
+            if let TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::Controller { controller, value },
+            } = track_event.kind
+            {
+                controller_values.insert((track_ports[track], channel, controller), value);
+            }
+        }
+    }
+
+    let event_count = events.len();
+
+    events.retain(|event| match event.kind {
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::Controller { controller, value },
+        } => {
+            controller_values.insert((track_ports[event.track], channel, controller), value)
+                != Some(value)
+        }
+        _ => true,
+    });
+
+    event_count - events.len()
+}
+
+/// Checks that every NoteOn has a NoteOff by counting them, and that no
+/// NoteOn turned into a NoteOff by losing its velocity.
+pub fn check_note_events(events: &[AbsoluteTrackEvent]) -> Result<(), ConversionError> {
+    let note_on_count = events
+        .iter()
+        .filter(|event| event.kind.is_note_on())
+        .count();
+    let note_off_count = events
+        .iter()
+        .filter(|event| event.kind.is_note_off())
+        .count();
+
+    if note_on_count != note_off_count {
+        return Err(ConversionError::InvariantViolation(format!(
+            "{} NoteOn events emitted with {} NoteOff events",
+            note_on_count, note_off_count
+        )));
+    }
+
+    // Receivers take NoteOn events without velocity for NoteOff events,
+    // leaving the note hanging
+    if let Some(event) = events
+        .iter()
+        .find(|event| event.kind.is_note_on() && (event.kind.velocity() == Some(u7::from(0))))
+    {
+        return Err(ConversionError::InvariantViolation(format!(
+            "NoteOn event {} has zero velocity",
+            describe_event(event)
+        )));
+    }
+
+    Ok(())
+}
+
+/// Appends the sorted events to their tracks with delta times computed per
+/// track. When trimming the leading silence, the position of the first event
+/// is subtracted from all tracks alike to keep them in sync. Returns the
+/// number of trimmed ticks.
+pub fn delta_encode<'a>(
+    midi_tracks: &mut [Track<'a>],
+    events: &[AbsoluteTrackEvent<'a>],
+    trim_leading_silence: bool,
+) -> Result<usize, ConversionError> {
+    let ticks_trimmed = match events.first() {
+        Some(event) if trim_leading_silence => event.ticks,
+        _ => 0,
+    };
+
+    let mut previous_track_events: Vec<Option<&AbsoluteTrackEvent>> = vec![None; midi_tracks.len()];
+
+    for event in events.iter() {
+        let ticks_before = match previous_track_events[event.track] {
+            Some(previous_event) if previous_event.ticks > event.ticks => {
+                return Err(ConversionError::InvariantViolation(format!(
+                    "event {} precedes event {}",
+                    describe_event(previous_event),
+                    describe_event(event)
+                )));
+            }
+            Some(previous_event) => previous_event.ticks,
+            None => ticks_trimmed,
+        };
+
+        previous_track_events[event.track] = Some(event);
+
+        midi_tracks[event.track].push(TrackEvent {
+            delta: u28::from((event.ticks - ticks_before) as u32),
+            kind: event.kind,
+        });
+    }
+
+    Ok(ticks_trimmed)
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::num::{u4, u7};
+
+    use super::*;
+
+    fn event(
+        track: usize,
+        ticks: usize,
+        kind: TrackEventKind<'static>,
+    ) -> AbsoluteTrackEvent<'static> {
+        AbsoluteTrackEvent {
+            track,
+            source: "Melody",
+            ticks,
+            ticks_event_start: ticks,
+            seconds: Seconds(0.0),
+            kind,
+        }
+    }
+
+    fn note_on(track: usize, ticks: usize) -> AbsoluteTrackEvent<'static> {
+        note_on_with_velocity(track, ticks, 64)
+    }
+
+    fn note_on_with_velocity(
+        track: usize,
+        ticks: usize,
+        velocity: u8,
+    ) -> AbsoluteTrackEvent<'static> {
+        event(
+            track,
+            ticks,
+            TrackEventKind::Midi {
+                channel: u4::from(0),
+                message: MidiMessage::NoteOn {
+                    key: u7::from(60),
+                    vel: u7::from(velocity),
+                },
+            },
+        )
+    }
+
+    fn note_off(track: usize, ticks: usize) -> AbsoluteTrackEvent<'static> {
+        event(
+            track,
+            ticks,
+            TrackEventKind::Midi {
+                channel: u4::from(0),
+                message: MidiMessage::NoteOff {
+                    key: u7::from(60),
+                    vel: u7::from(0),
+                },
+            },
+        )
+    }
+
+    fn controller(channel: u8, controller: u8, value: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: u4::from(channel),
+            message: MidiMessage::Controller {
+                controller: u7::from(controller),
+                value: u7::from(value),
+            },
+        }
+    }
+
+    #[test]
+    fn dedup_controllers_drops_repeated_values() {
+        let setup_tracks = vec![
+            vec![TrackEvent {
+                delta: u28::from(0),
+                kind: controller(1, 10, 64),
+            }],
+            Track::new(),
+        ];
+
+        let mut events = vec![
+            event(0, 0, controller(0, 7, 100)),
+            event(0, 10, controller(0, 7, 100)),
+            event(0, 20, controller(0, 10, 64)),
+            event(0, 30, controller(0, 7, 100)),
+            event(0, 40, controller(1, 7, 100)),
+            event(0, 50, controller(1, 10, 64)),
+            event(0, 60, controller(0, 7, 90)),
+            event(0, 70, controller(0, 7, 100)),
+            event(1, 80, controller(0, 7, 100)),
+            event(1, 90, controller(0, 7, 100)),
+        ];
+
+        assert_eq!(dedup_controllers(&mut events, &setup_tracks, &[0, 1]), 4);
+        assert_eq!(
+            events.iter().map(|event| event.ticks).collect::<Vec<_>>(),
+            [0, 20, 40, 60, 70, 80]
+        );
+    }
+
+    fn deltas(midi_track: &Track) -> Vec<u32> {
+        midi_track
+            .iter()
+            .map(|track_event| track_event.delta.as_int())
+            .collect()
+    }
+
+    #[test]
+    fn check_note_events_rejects_unbalanced_notes() {
+        let events = [
+            note_on(0, 0),
+            note_off(0, 512),
+            note_on(0, 1024),
+            note_off(0, 1536),
+        ];
+        assert!(check_note_events(&events).is_ok());
+        assert!(check_note_events(&[]).is_ok());
+
+        for events in [
+            &[note_on(0, 0)][..],
+            &[note_off(0, 0)],
+            &[note_on(0, 0), note_off(0, 512), note_off(0, 1024)],
+            &[note_on(0, 0), note_on(1, 0), note_off(0, 512)],
+        ] {
+            assert!(matches!(
+                check_note_events(events),
+                Err(ConversionError::InvariantViolation(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn check_note_events_rejects_silent_note_ons() {
+        let events = [note_on_with_velocity(0, 0, 0), note_off(0, 512)];
+
+        assert!(matches!(
+            check_note_events(&events),
+            Err(ConversionError::InvariantViolation(_))
+        ));
+    }
+
+    #[test]
+    fn delta_encode_trims_leading_silence() {
+        let events = [note_on(0, 2048), note_on(1, 2560), note_on(0, 3072)];
+
+        let mut midi_tracks = vec![Track::new(); 2];
+        assert_eq!(delta_encode(&mut midi_tracks, &events, true).unwrap(), 2048);
+        assert_eq!(deltas(&midi_tracks[0]), [0, 1024]);
+        assert_eq!(deltas(&midi_tracks[1]), [512]);
+
+        let mut midi_tracks = vec![Track::new(); 2];
+        assert_eq!(delta_encode(&mut midi_tracks, &events, false).unwrap(), 0);
+        assert_eq!(deltas(&midi_tracks[0]), [2048, 1024]);
+        assert_eq!(deltas(&midi_tracks[1]), [2560]);
+    }
+
+    #[test]
+    fn delta_encode_rejects_unsorted_events() {
+        let events = [note_on(0, 1024), note_on(0, 512)];
+
+        let mut midi_tracks = vec![Track::new(); 1];
+        assert!(matches!(
+            delta_encode(&mut midi_tracks, &events, false),
+            Err(ConversionError::InvariantViolation(_))
+        ));
+    }
+}
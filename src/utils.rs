@@ -1,7 +1,15 @@
+use std::cmp::Ordering;
 use std::error::Error;
 use std::fmt;
+use std::num::{NonZeroU32, NonZeroU8, NonZeroUsize};
 use std::str::FromStr;
 
+use clap::ArgEnum;
+use midly::num::{u24, u7};
+use midly::{Format, Fps, SmpteTime};
+
+use crate::tempo_map::TempoMap;
+
 #[derive(Debug, Copy, Clone)]
 pub struct Seconds(pub f64);
 
@@ -11,10 +19,56 @@ impl Seconds {
         Seconds(frame as f64 / sample_rate as f64)
     }
 
-    pub fn as_midi_ticks(&self, midi_bpm: f64, midi_ticks_per_beat: usize) -> usize {
-        assert!(midi_bpm > 0.0);
-        assert!(midi_ticks_per_beat > 0);
-        (self.0 * (midi_bpm / 60.0) * (midi_ticks_per_beat as f64)) as usize
+    pub fn as_midi_ticks(
+        &self,
+        tempo_map: &TempoMap,
+        midi_ticks_per_beat: NonZeroUsize,
+        tick_rounding: TickRounding,
+    ) -> usize {
+        let ticks = tempo_map.beats(*self) * (midi_ticks_per_beat.get() as f64);
+
+        match tick_rounding {
+            TickRounding::Floor => ticks.floor() as usize,
+            TickRounding::Round => ticks.round() as usize,
+            TickRounding::Ceil => ticks.ceil() as usize,
+        }
+    }
+}
+
+/// Rounding of the positions falling between two MIDI ticks. All of these are
+/// monotonic, NoteOff events can't get ahead of their NoteOn events.
+#[derive(Debug, Copy, Clone, ArgEnum)]
+pub enum TickRounding {
+    /// Round down, the behavior of earlier versions
+    Floor,
+
+    /// Round to the nearest tick
+    Round,
+
+    /// Round up
+    Ceil,
+}
+
+// Seconds are computed from integer frames and sample rates, they are never
+// NaN. The comparisons follow `f64::total_cmp` to make the ordering total.
+impl PartialEq for Seconds {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Seconds {}
+
+impl PartialOrd for Seconds {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Seconds {
+    fn cmp(&self, other: &Self) -> Ordering {
+        debug_assert!(!self.0.is_nan() && !other.0.is_nan());
+        self.0.total_cmp(&other.0)
     }
 }
 
@@ -39,6 +93,93 @@ impl fmt::Display for Seconds {
     }
 }
 
+/// SMPTE timecode without the frame rate, as given on the command line.
+#[derive(Debug, Copy, Clone)]
+pub struct SmpteTimecode {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub frame: u8,
+    pub subframe: u8,
+}
+
+impl SmpteTimecode {
+    pub fn as_smpte_time(&self, fps: Fps) -> Option<SmpteTime> {
+        SmpteTime::new(
+            self.hour,
+            self.minute,
+            self.second,
+            self.frame,
+            self.subframe,
+            fps,
+        )
+    }
+}
+
+/// Linear mapping of note values from the value range of a model onto a
+/// range of MIDI keys.
+#[derive(Debug, Copy, Clone)]
+pub struct NoteRange {
+    pub value_min: f64,
+    pub value_max: f64,
+    pub key_min: f64,
+    pub key_max: f64,
+}
+
+impl NoteRange {
+    pub fn contains(&self, value: f64) -> bool {
+        (self.value_min..=self.value_max).contains(&value)
+    }
+
+    /// Maps a note value to a fractional MIDI key, values outside of the
+    /// value range are clamped.
+    pub fn remap(&self, value: f64) -> f64 {
+        let value = value.clamp(self.value_min, self.value_max);
+
+        self.key_min
+            + (value - self.value_min) * (self.key_max - self.key_min)
+                / (self.value_max - self.value_min)
+    }
+}
+
+/// Maps a pan value from the -1.0 (left) - 1.0 (right) range to MIDI pan.
+pub fn midi_pan(pan: f64) -> u7 {
+    u7::from((64.0 + (pan.clamp(-1.0, 1.0) * 63.5)) as u8)
+}
+
+/// Maps a level from the 0.0-1.0 range to MIDI velocity. Zero velocity is
+/// avoided, NoteOn events with zero velocity are treated as NoteOff events.
+pub fn midi_velocity(level: f64) -> u8 {
+    (level * 127.0).round().clamp(1.0, 127.0) as u8
+}
+
+/// Tempo range of the MIDI tempo meta event, limited by the 24-bit
+/// microseconds per beat value at the slow end and by the microsecond
+/// resolution at the fast end.
+pub const MIDI_BPM_RANGE: (f64, f64) = (60_000_000.0 / 0xFF_FFFF as f64, 60_000.0);
+
+/// Microseconds per beat of a tempo meta event, tempos outside of
+/// `MIDI_BPM_RANGE` are clamped into it.
+pub fn midi_tempo(bpm: f64) -> u24 {
+    let (min_bpm, max_bpm) = MIDI_BPM_RANGE;
+    u24::from((60_000_000.0 / bpm).clamp(60_000_000.0 / max_bpm, 60_000_000.0 / min_bpm) as u32)
+}
+
+/// Human-readable name of a MIDI note for diagnostics, middle C (60) being C4
+/// unless shifted by `octave_offset`. Keys outside of the MIDI range are named
+/// too, for reporting out-of-range notes.
+pub fn midi_note_name(key: isize, octave_offset: i8) -> String {
+    const NOTE_NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+
+    format!(
+        "{}{}",
+        NOTE_NAMES[key.rem_euclid(12) as usize],
+        key.div_euclid(12) - 1 + octave_offset as isize
+    )
+}
+
 pub fn parse_positive_literal<'a, T>(input: &str) -> Result<T, Box<dyn 'a + Error + Send + Sync>>
 where
     T: FromStr + Default + PartialOrd,
@@ -52,3 +193,95 @@ where
         Err("not a positive literal".into())
     }
 }
+
+pub fn parse_nonzero_usize(input: &str) -> Result<NonZeroUsize, Box<dyn Error + Send + Sync>> {
+    NonZeroUsize::new(input.parse()?).ok_or_else(|| "not a positive literal".into())
+}
+
+pub fn parse_nonzero_u8(input: &str) -> Result<NonZeroU8, Box<dyn Error + Send + Sync>> {
+    NonZeroU8::new(input.parse()?).ok_or_else(|| "not a positive literal".into())
+}
+
+pub fn parse_nonzero_u32(input: &str) -> Result<NonZeroU32, Box<dyn Error + Send + Sync>> {
+    NonZeroU32::new(input.parse()?).ok_or_else(|| "not a positive literal".into())
+}
+
+pub fn parse_midi_key(input: &str) -> Result<u7, Box<dyn Error + Send + Sync>> {
+    u7::try_from(input.parse::<u8>()?).ok_or_else(|| "not a MIDI key (expected 0-127)".into())
+}
+
+/// Parses a `LAYER=VALUE` pair, the layer name may contain `=` characters.
+pub fn parse_layer_value<'a, T>(
+    input: &str,
+) -> Result<(String, T), Box<dyn 'a + Error + Send + Sync>>
+where
+    T: FromStr,
+    <T as FromStr>::Err: 'a + Error + Send + Sync,
+{
+    let (layer_name, value) = input
+        .rsplit_once('=')
+        .ok_or("not a layer assignment (expected LAYER=VALUE)")?;
+
+    Ok((layer_name.to_owned(), value.parse::<T>()?))
+}
+
+/// Parses an SMPTE timecode in the `hh:mm:ss:ff.ff` format, the sub-frames are optional.
+pub fn parse_smpte_timecode(input: &str) -> Result<SmpteTimecode, Box<dyn Error + Send + Sync>> {
+    let (input, subframe) = match input.split_once('.') {
+        Some((input, subframe)) => (input, subframe.parse::<u8>()?),
+        None => (input, 0),
+    };
+
+    let fields = input
+        .split(':')
+        .map(|field| field.parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let [hour, minute, second, frame] = fields[..] {
+        if (hour > 23) || (minute > 59) || (second > 59) || (frame > 29) || (subframe > 99) {
+            Err("SMPTE timecode field out of range".into())
+        } else {
+            Ok(SmpteTimecode {
+                hour,
+                minute,
+                second,
+                frame,
+                subframe,
+            })
+        }
+    } else {
+        Err("not an SMPTE timecode (expected hh:mm:ss:ff.ff)".into())
+    }
+}
+
+pub fn parse_smpte_fps(input: &str) -> Result<Fps, Box<dyn Error + Send + Sync>> {
+    match input {
+        "24" => Ok(Fps::Fps24),
+        "25" => Ok(Fps::Fps25),
+        "29.97" => Ok(Fps::Fps29),
+        "30" => Ok(Fps::Fps30),
+        _ => Err("unsupported SMPTE frame rate (expected 24, 25, 29.97 or 30)".into()),
+    }
+}
+
+pub fn parse_midi_format(input: &str) -> Result<Format, Box<dyn Error + Send + Sync>> {
+    match input {
+        "0" => Ok(Format::SingleTrack),
+        "1" => Ok(Format::Parallel),
+        _ => Err("unsupported MIDI file format (expected 0 or 1)".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn midi_tempo_clamped_into_range() {
+        assert_eq!(midi_tempo(120.0), u24::from(500_000));
+        assert_eq!(midi_tempo(1e-9), u24::max_value());
+        assert_eq!(midi_tempo(1e9), u24::from(1_000));
+        assert_eq!(midi_tempo(MIDI_BPM_RANGE.0), u24::max_value());
+        assert_eq!(midi_tempo(MIDI_BPM_RANGE.1), u24::from(1_000));
+    }
+}
@@ -0,0 +1,422 @@
+use std::fmt;
+
+use crate::sv_model::LayerMismatch;
+use crate::utils::Seconds;
+
+/// Problems of a project not preventing its conversion. Layer names are the
+/// names used in the MIDI file, note names are already shifted by the
+/// octave offset.
+#[derive(Debug, Clone)]
+pub enum Warning {
+    TempoOutOfRange {
+        bpm: f64,
+        min_bpm: f64,
+        max_bpm: f64,
+    },
+
+    DuplicateModelIds {
+        model_ids: Vec<usize>,
+    },
+
+    OrphanedDataset {
+        dataset_id: usize,
+    },
+
+    OrphanedModel {
+        model_id: usize,
+        model_name: String,
+    },
+
+    /// Point frames looking like multiples of the model resolution instead
+    /// of samples.
+    ResolutionFrames {
+        model_id: usize,
+        model_name: String,
+        resolution: usize,
+    },
+
+    UnmatchedChannelMapEntry {
+        layer_name: String,
+    },
+
+    UnmatchedSplitLayer {
+        layer_name: String,
+    },
+
+    UnmatchedTextTrack {
+        text_layer_name: String,
+        notes_layer_name: String,
+    },
+
+    ChannelMapEntryOverriddenBySplit {
+        layer_name: String,
+        channel: u8,
+    },
+
+    TooManyNotesLayers,
+
+    SharedChannel {
+        layer_name: String,
+        other_layer_name: String,
+        channel: u8,
+    },
+
+    ReleaseTriggerChannelShared {
+        layer_name: String,
+        channel: u8,
+    },
+
+    SampleRateMismatch {
+        layer_name: String,
+        sample_rate: usize,
+        main_sample_rate: usize,
+    },
+
+    SharedModel {
+        model_id: usize,
+        layer_names: Vec<String>,
+    },
+
+    /// Layer left out under `--lenient`, its model or dataset not matching
+    /// the layer type.
+    LayerSkipped {
+        layer_name: String,
+        mismatch: LayerMismatch,
+    },
+
+    UnknownColour {
+        colour: String,
+        layer_name: String,
+    },
+
+    NonAsciiInstrumentName {
+        layer_name: String,
+    },
+
+    PanOverriddenBySpread {
+        layer_name: String,
+    },
+
+    MissingValueRange {
+        layer_name: String,
+    },
+
+    ValueNotInScale {
+        value: f64,
+        layer_name: String,
+        seconds: Seconds,
+    },
+
+    ValueOutOfRange {
+        value: f64,
+        layer_name: String,
+        seconds: Seconds,
+        clamped_note_name: String,
+    },
+
+    /// Zero-duration note, not even audible.
+    ZeroDurationNote {
+        point_index: usize,
+        note_name: String,
+        frame: usize,
+        layer_name: String,
+        seconds: Seconds,
+    },
+
+    /// Single-frame note, usually drawn accidentally in Sonic Visualiser.
+    CollapsedNote {
+        point_index: usize,
+        note_name: String,
+        frame: usize,
+        layer_name: String,
+        seconds: Seconds,
+    },
+
+    /// A note starting and ending at the same tick, the note name is missing
+    /// for instants layers.
+    InsufficientResolution {
+        note_name: Option<String>,
+        layer_type: String,
+        layer_name: String,
+        seconds: Seconds,
+    },
+
+    NoteOutOfMidiRange {
+        note_name: String,
+        layer_name: String,
+        seconds: Seconds,
+    },
+
+    NonAsciiLabel {
+        label: String,
+        layer_type: String,
+        layer_name: String,
+        seconds: Seconds,
+    },
+
+    ExcessivePolyphony {
+        seconds: Seconds,
+    },
+
+    NoteOverlap {
+        note_name: String,
+        seconds: Seconds,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| format!("'{}'", name.escape_default()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        match self {
+            Warning::TempoOutOfRange {
+                bpm,
+                min_bpm,
+                max_bpm,
+            } => write!(
+                f,
+                "tempo {}bpm is outside of the MIDI tempo range ({:.2}-{}bpm), clamped in the tempo event",
+                bpm, min_bpm, max_bpm
+            ),
+            Warning::DuplicateModelIds { model_ids } => write!(
+                f,
+                "project has duplicate model ids: {}",
+                model_ids
+                    .iter()
+                    .map(|model_id| model_id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Warning::OrphanedDataset { dataset_id } => {
+                write!(f, "dataset {} is not referenced by any model", dataset_id)
+            }
+            Warning::OrphanedModel {
+                model_id,
+                model_name,
+            } => write!(
+                f,
+                "model {} ('{}') is not referenced by any layer",
+                model_id,
+                model_name.escape_default()
+            ),
+            Warning::ResolutionFrames {
+                model_id,
+                model_name,
+                resolution,
+            } => write!(
+                f,
+                "frames of model {} ('{}') look like multiples of its resolution ({}) instead of samples",
+                model_id,
+                model_name.escape_default(),
+                resolution
+            ),
+            Warning::UnmatchedChannelMapEntry { layer_name } => write!(
+                f,
+                "channel map entry '{}' matches no notes layer",
+                layer_name.escape_default()
+            ),
+            Warning::UnmatchedSplitLayer { layer_name } => write!(
+                f,
+                "split layer '{}' matches no notes layer",
+                layer_name.escape_default()
+            ),
+            Warning::UnmatchedTextTrack {
+                text_layer_name,
+                notes_layer_name,
+            } => write!(
+                f,
+                "text track entry '{}={}' matches no text layer or notes layer",
+                text_layer_name.escape_default(),
+                notes_layer_name.escape_default()
+            ),
+            Warning::ChannelMapEntryOverriddenBySplit {
+                layer_name,
+                channel,
+            } => write!(
+                f,
+                "channel map entry '{}' (channel {}) overridden by its split",
+                layer_name.escape_default(),
+                channel
+            ),
+            Warning::TooManyNotesLayers => write!(
+                f,
+                "project has more notes layers than available MIDI channels"
+            ),
+            Warning::SharedChannel {
+                layer_name,
+                other_layer_name,
+                channel,
+            } => write!(
+                f,
+                "notes layers '{}' and '{}' share MIDI channel {}",
+                other_layer_name.escape_default(),
+                layer_name.escape_default(),
+                channel
+            ),
+            Warning::ReleaseTriggerChannelShared {
+                layer_name,
+                channel,
+            } => write!(
+                f,
+                "notes layer '{}' shares MIDI channel {} with the release triggers",
+                layer_name.escape_default(),
+                channel
+            ),
+            Warning::SampleRateMismatch {
+                layer_name,
+                sample_rate,
+                main_sample_rate,
+            } => write!(
+                f,
+                "layer '{}' has a sample rate of {} Hz, differing from the {} Hz of the main model",
+                layer_name.escape_default(),
+                sample_rate,
+                main_sample_rate
+            ),
+            Warning::SharedModel {
+                model_id,
+                layer_names,
+            } => write!(
+                f,
+                "model {} is exported by multiple layers: {}",
+                model_id,
+                join(layer_names)
+            ),
+            Warning::LayerSkipped {
+                layer_name,
+                mismatch,
+            } => write!(
+                f,
+                "layer '{}' skipped: {}",
+                layer_name.escape_default(),
+                mismatch
+            ),
+            Warning::UnknownColour { colour, layer_name } => write!(
+                f,
+                "unknown colour '{}' of layer '{}'",
+                colour.escape_default(),
+                layer_name.escape_default()
+            ),
+            Warning::NonAsciiInstrumentName { layer_name } => write!(
+                f,
+                "non-ASCII instrument name '{}'",
+                layer_name.escape_default()
+            ),
+            Warning::PanOverriddenBySpread { layer_name } => write!(
+                f,
+                "pan of notes layer '{}' overridden by the stereo spread",
+                layer_name.escape_default()
+            ),
+            Warning::MissingValueRange { layer_name } => write!(
+                f,
+                "notes layer '{}' has no value range, note range remapping skipped",
+                layer_name.escape_default()
+            ),
+            Warning::ValueNotInScale {
+                value,
+                layer_name,
+                seconds,
+            } => write!(
+                f,
+                "note value {} on notes layer '{}' at {} not found in the scale",
+                value,
+                layer_name.escape_default(),
+                seconds
+            ),
+            Warning::ValueOutOfRange {
+                value,
+                layer_name,
+                seconds,
+                clamped_note_name,
+            } => write!(
+                f,
+                "note value {} outside of the value range of notes layer '{}' at {}, clamped to {}",
+                value,
+                layer_name.escape_default(),
+                seconds,
+                clamped_note_name
+            ),
+            Warning::ZeroDurationNote {
+                point_index,
+                note_name,
+                frame,
+                layer_name,
+                seconds,
+            } => write!(
+                f,
+                "zero-duration note #{} ({}, frame {}) on notes layer '{}' at {}",
+                point_index,
+                note_name,
+                frame,
+                layer_name.escape_default(),
+                seconds
+            ),
+            Warning::CollapsedNote {
+                point_index,
+                note_name,
+                frame,
+                layer_name,
+                seconds,
+            } => write!(
+                f,
+                "collapsed note #{} ({}, frame {}) on notes layer '{}' at {}",
+                point_index,
+                note_name,
+                frame,
+                layer_name.escape_default(),
+                seconds
+            ),
+            Warning::InsufficientResolution {
+                note_name,
+                layer_type,
+                layer_name,
+                seconds,
+            } => write!(
+                f,
+                "insufficient resolution to represent MIDI note{} on {} layer '{}' at {}",
+                note_name
+                    .as_ref()
+                    .map_or_else(String::new, |note_name| format!(" {}", note_name)),
+                layer_type,
+                layer_name.escape_default(),
+                seconds
+            ),
+            Warning::NoteOutOfMidiRange {
+                note_name,
+                layer_name,
+                seconds,
+            } => write!(
+                f,
+                "note {} out of MIDI range on notes layer '{}' at {}",
+                note_name,
+                layer_name.escape_default(),
+                seconds
+            ),
+            Warning::NonAsciiLabel {
+                label,
+                layer_type,
+                layer_name,
+                seconds,
+            } => write!(
+                f,
+                "non-ASCII label '{}' on {} layer '{}' at {}",
+                label.escape_default(),
+                layer_type,
+                layer_name.escape_default(),
+                seconds
+            ),
+            Warning::ExcessivePolyphony { seconds } => {
+                write!(f, "excessive polyphony at {}", seconds)
+            }
+            Warning::NoteOverlap { note_name, seconds } => {
+                write!(f, "note overlap ({}) at {}", note_name, seconds)
+            }
+        }
+    }
+}